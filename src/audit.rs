@@ -0,0 +1,310 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use crate::deploy::template::Vars;
+use crate::identity::user_name;
+use crate::integrity::sha256;
+use crate::report::{RunReport, Status};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What the first entry gives as the hash of the entry before it.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Whether an entry was written as a run started or once it ended.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditEvent {
+    Started,
+    Finished,
+}
+
+/// The start or the outcome of a run in the audit log. Each entry holds
+/// the hash of the one before, so changing or dropping an entry breaks
+/// every hash after it. A run without a `finished` entry was killed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Position in the log, from 1.
+    pub seq: u64,
+    pub ts_ms: u64,
+    pub event: AuditEvent,
+    pub uid: u32,
+    pub user: String,
+    /// Who logged in before becoming `user` with sudo or su, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub login_uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub login_user: Option<String>,
+    pub hostname: String,
+    pub plan: String,
+    pub run_id: String,
+    /// Variables the plan ran with, secrets masked.
+    pub vars: BTreeMap<String, String>,
+    /// `Config::hash` of the config the plan ran with.
+    pub config_hash: String,
+    /// The outcome, none for a `started` entry.
+    pub status: Option<Status>,
+    pub error: Option<String>,
+    pub prev: String,
+    /// SHA-256 of the entry without this field.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// An entry for the start of the run of `report` by the current user on
+    /// this host, chained when it is appended.
+    pub fn started(report: &RunReport, vars: &Vars, config_hash: &str) -> AuditEntry {
+        Self::new(AuditEvent::Started, report, vars, config_hash)
+    }
+
+    /// An entry for the outcome of the finished `report`.
+    pub fn finished(report: &RunReport, vars: &Vars, config_hash: &str) -> AuditEntry {
+        Self::new(AuditEvent::Finished, report, vars, config_hash)
+    }
+
+    fn new(event: AuditEvent, report: &RunReport, vars: &Vars, config_hash: &str) -> AuditEntry {
+        let uid = current_uid();
+        let login_uid = login_uid();
+        let finished = event == AuditEvent::Finished;
+        AuditEntry {
+            seq: 0,
+            ts_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            event,
+            uid,
+            user: user_name(uid),
+            login_uid,
+            login_user: login_uid.map(user_name).or_else(|| env::var("SUDO_USER").ok()),
+            hostname: hostname(),
+            plan: report.plan.clone(),
+            run_id: report.id.clone(),
+            vars: vars.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            config_hash: config_hash.to_owned(),
+            status: if finished { Some(report.status) } else { None },
+            error: if finished { report.error.clone() } else { None },
+            prev: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    fn digest(&self) -> Result<String, Error> {
+        let mut e = self.clone();
+        e.hash.clear();
+        Ok(sha256(serde_json::to_string(&e)?.as_bytes()))
+    }
+}
+
+/// An append-only file of `AuditEntry`s, one JSON object per line.
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    /// Length of the file as far as it was read, and `seq` and `hash` of
+    /// the last entry up to there.
+    read_len: u64,
+    last: Option<(u64, String)>,
+}
+
+impl AuditLog {
+    /// Opens the log at `path`, creating it and its directory if needed.
+    /// Only its owner may read a new log.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<AuditLog, Error> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = OpenOptions::new();
+        options.read(true).append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&path)?;
+        let mut log = AuditLog { path, file, read_len: 0, last: None };
+        log.file.lock_shared()?;
+        let read = log.catch_up();
+        log.file.unlock()?;
+        read.map(|_| log)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Chains `entry` to the last entry and appends it. The file is locked
+    /// meanwhile so runs finishing together still make a single chain.
+    pub fn append(&mut self, mut entry: AuditEntry) -> Result<AuditEntry, Error> {
+        self.file.lock()?;
+        let result = self.catch_up().and_then(|_| {
+            entry.seq = self.last.as_ref().map_or(1, |(seq, _)| seq + 1);
+            entry.prev = self.last.as_ref().map_or(GENESIS, |(_, hash)| hash).to_owned();
+            entry.hash = entry.digest()?;
+            let line = serde_json::to_string(&entry)? + "\n";
+            self.file.write_all(line.as_bytes())?;
+            self.file.sync_data()?;
+            self.read_len += line.len() as u64;
+            self.last = Some((entry.seq, entry.hash.clone()));
+            Ok(entry)
+        });
+        self.file.unlock()?;
+        result
+    }
+
+    /// Reads what other runs appended since the log was last read, the
+    /// whole log if it got shorter.
+    fn catch_up(&mut self) -> Result<(), Error> {
+        let len = self.file.metadata()?.len();
+        if len < self.read_len {
+            self.read_len = 0;
+            self.last = None;
+        }
+        if len == self.read_len {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(self.read_len))?;
+        let mut s = String::new();
+        self.file.read_to_string(&mut s)?;
+        if let Some(l) = s.lines().rev().find(|l| !l.trim().is_empty()) {
+            let e: AuditEntry = serde_json::from_str(l)?;
+            self.last = Some((e.seq, e.hash));
+        }
+        self.read_len += s.len() as u64;
+        Ok(())
+    }
+
+    /// Checks that every entry of the log at `path` is intact and follows
+    /// the one before, returning the number of entries. Entries cut off the
+    /// end cannot be told apart from runs that never happened.
+    pub fn verify<P: AsRef<Path>>(path: P) -> Result<u64, Error> {
+        let path = path.as_ref();
+        let mut prev = GENESIS.to_owned();
+        let mut seq = 0;
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let broken = |why: String| Error::Integrity(format!("`{}` line {}: {}", path.display(), i + 1, why));
+            let entry: AuditEntry = serde_json::from_str(&line).map_err(|e| broken(format!("not an entry: {}", e)))?;
+            if entry.seq != seq + 1 {
+                return Err(broken(format!("expected entry {}, found {}", seq + 1, entry.seq)));
+            }
+            if entry.prev != prev {
+                return Err(broken("does not follow the entry before".to_owned()));
+            }
+            if entry.hash != entry.digest()? {
+                return Err(broken("was changed after it was written".to_owned()));
+            }
+            seq = entry.seq;
+            prev = entry.hash;
+        }
+        Ok(seq)
+    }
+}
+
+/// The user who logged in, kept by the kernel across sudo and su, or the
+/// one sudo names when there is no login uid.
+fn login_uid() -> Option<u32> {
+    fs::read_to_string("/proc/self/loginuid").ok()
+        .and_then(|s| s.trim().parse().ok())
+        .filter(|u| *u != u32::MAX)
+        .or_else(|| env::var("SUDO_UID").ok().and_then(|s| s.parse().ok()))
+}
+
+#[cfg(unix)]
+fn current_uid() -> u32 {
+    // SAFETY: getuid cannot fail.
+    unsafe { libc::getuid() }
+}
+
+/// No uids here, `u32::MAX` stands for none like in `loginuid`.
+#[cfg(not(unix))]
+fn current_uid() -> u32 {
+    u32::MAX
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: gethostname writes at most `buf.len()` bytes into `buf`.
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::new();
+    }
+    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditEntry, AuditEvent, AuditLog, GENESIS};
+    use crate::report::{RunReport, Status};
+    use crate::testutil::TempDir;
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[test]
+    fn test_audit_log() {
+        let tmp = TempDir::new("audit");
+        let path = tmp.join("logs/audit.jsonl");
+        let mut vars = HashMap::new();
+        vars.insert("env".to_owned(), "prod".to_owned());
+
+        let mut log = AuditLog::open(&path).unwrap();
+        let mut report = RunReport::new("deploy");
+        report.finish(Ok(()));
+        let first = log.append(AuditEntry::started(&report, &vars, "abc")).unwrap();
+        assert_eq!((first.event, first.status), (AuditEvent::Started, None));
+        assert_eq!((first.seq, first.prev.as_str()), (1, GENESIS));
+        assert_eq!(first.vars.get("env").map(String::as_str), Some("prod"));
+        #[cfg(unix)]
+        assert_eq!(first.uid, unsafe { libc::getuid() });
+        report.finish(Err("boom".to_owned()));
+        let second = AuditLog::open(&path).unwrap().append(AuditEntry::finished(&report, &vars, "abc")).unwrap();
+        assert_eq!((second.seq, &second.prev), (2, &first.hash));
+        assert_eq!((second.status, second.error.as_deref()), (Some(Status::Failed), Some("boom")));
+        // `log` still follows the entry appended through the other handle.
+        let third = log.append(AuditEntry::finished(&report, &vars, "abc")).unwrap();
+        assert_eq!((third.seq, &third.prev), (3, &second.hash));
+        assert_eq!(AuditLog::verify(&path).unwrap(), 3);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o077, 0);
+        }
+
+        let good = fs::read_to_string(&path).unwrap();
+        fs::write(&path, good.replace("\"prod\"", "\"dev\"")).unwrap();
+        let e = AuditLog::verify(&path).unwrap_err();
+        assert!(format!("{}", e).ends_with("line 1: was changed after it was written"), "{}", e);
+        let lines: Vec<&str> = good.lines().collect();
+        fs::write(&path, format!("{}\n", lines[1])).unwrap();
+        assert!(format!("{}", AuditLog::verify(&path).unwrap_err()).ends_with("expected entry 1, found 2"));
+    }
+}
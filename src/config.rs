@@ -21,58 +21,711 @@
 //! SOFTWARE.
 //! 
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::fs::File;
-use std::io::Read;
-use serde_json;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::fs;
 use serde::{Serialize, Deserialize};
 use crate::Error;
-use crate::deploy::CommandSet;
-use std::process::exit;
+use crate::deploy::{CommandSet, DeployTask};
+use crate::deploy::command::ExecutableCommand;
+use crate::deploy::template::Vars;
+use crate::daemon::ScheduleEntry;
+use crate::history::History;
+use crate::integrity;
+use crate::logdir::{LogDir, Retention};
+use crate::policy::Policy;
+use crate::runlock::Locks;
+use crate::secret::{SecretSource, Secrets, MASK};
+use crate::vault::Vault;
 
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
 pub struct Config {
+    #[serde(default)]
     pub config_dir: String,
+    #[serde(default)]
     pub command_config_file: String,
+    /// More command pools, merged with `command_config_file` into one set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command_config_files: Vec<String>,
+    /// Plan files, entries may be glob patterns like `plans/*.json`.
+    #[serde(default)]
     pub plan_files:Vec<String>,
+    #[serde(default)]
     pub taskfiles: HashMap<String, String>,
+    /// Glob patterns of task files, named after the `name` inside each file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub task_globs: Vec<String>,
+    /// Other config files (or glob patterns) merged into this one, paths in
+    /// them stay relative to their own `config_dir`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Variables for templates in every plan.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub vars: Vars,
+    /// Environment variables set for every command.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Where finished runs are recorded, none if empty.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub history_dir: String,
+    /// Where every run gets a directory of command logs, none if empty.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub log_dir: String,
+    /// Which runs are kept in `log_dir`.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub log_retention: Retention,
+    /// Where run locks are kept, shared by everyone who may run the plans
+    /// like `/run/queenbee/locks`. Plans are not locked if empty.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub lock_dir: String,
+    /// Tells configs sharing `lock_dir` apart, the path of the config file
+    /// if empty. Copies of one config at different paths need the same
+    /// scope to lock each other's plans.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub lock_scope: String,
+    /// How long a run waits for a lock held by another run, it fails at
+    /// once if 0.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub lock_timeout_secs: u64,
+    /// Lets a single plan of any config sharing `lock_dir` run at a time.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub global_lock: bool,
+    /// Append-only log of who ran which plan and how it went, none if
+    /// empty. See `AuditLog`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub audit_log: String,
+    /// Restrictions on the commands allowed to run, see `Policy`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub policy_file: String,
+    /// Secrets by name, exposed to templates as `{{ secrets.<name> }}` and
+    /// masked in all output.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub secrets: HashMap<String, SecretSource>,
+    /// The encrypted vault managed by `queenbee secrets`, `secrets.vault`
+    /// in `config_dir` if empty. Its entries are secrets too.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub vault_file: String,
+    /// When `queenbee daemon` runs which plans.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedules: Vec<ScheduleEntry>,
+    /// Named overlays, at most one of them is applied with `with_profile`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub profiles: HashMap<String, Profile>,
+    #[serde(skip_serializing, skip_deserializing)]
+    profile: Option<String>,
+    /// The file the config was loaded from.
+    #[serde(skip_serializing, skip_deserializing)]
+    path: Option<PathBuf>,
+    /// Tasks given in memory, used before looking into `taskfiles`.
+    #[serde(skip_serializing, skip_deserializing)]
+    tasks: HashMap<String, DeployTask>,
     #[serde(skip_serializing, skip_deserializing)]
     commandpool: Option<CommandSet>
 }
 
+/// Values replacing the ones of the config when the profile is selected.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub vars: Vars,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Commands replacing the ones with the same `idx` in the command pool.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<ExecutableCommand>,
+    /// Variables per plan name, overriding the plan's own `vars`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub plan_defaults: HashMap<String, Vars>,
+}
+
+/// One effective setting and where it came from, as listed by `show`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConfigValue {
+    pub key: String,
+    pub value: String,
+    pub source: String,
+}
+
 impl Config {
+    /// Loads the config at `config_path`, or the one found by `discover`
+    /// when no path is given.
     pub fn new(config_path: Option<String>) -> Result<Config, Error> {
-        static CONFIG_FILE: &str = "config.json";
         let config_path = match config_path {
-            Some(a) => a,
-            None => CONFIG_FILE.to_owned(),
+            Some(a) => PathBuf::from(a),
+            None => Self::discover()?,
         };
-        let mut f = File::open(PathBuf::from(config_path))?;
-        let mut s = String::new();
-        f.read_to_string(&mut s)?;
-        let mut config: Config = serde_json::from_str(&s)?;
+        let mut config = Self::load(&config_path, &mut Vec::new(), &mut Vec::new(), true)?;
+        config.path = Some(fs::canonicalize(&config_path)?);
         config.load_commandpool()?;
         Ok(config)
     }
 
-    pub fn get_commandpool(&mut self) -> CommandSet {
-        if self.commandpool.is_none() {
-            match self.load_commandpool() {
-                Ok(_) => {},
-                Err(e) => {
-                    eprintln!("Error loading commandpool: {:?}!", e);
-                    exit(1);
-                }
+    /// Finds the config file when `--config` is not given: `QUEENBEE_CONFIG`,
+    /// then `config.json` in the current directory or any parent, then
+    /// `$XDG_CONFIG_HOME/queenbee/` and finally `/etc/queenbee/`.
+    pub fn discover() -> Result<PathBuf, Error> {
+        let xdg = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+        let found = discover_from(
+            env::var_os("QUEENBEE_CONFIG").map(PathBuf::from),
+            &env::current_dir()?,
+            xdg,
+            Path::new("/etc/queenbee"),
+        );
+        found.ok_or_else(|| Error::Failed(format!("No `{}` found in the current directory, its parents, the XDG config directory or /etc/queenbee!", CONFIG_FILE)))
+    }
+
+    /// A config living only in memory, for plans built in code: the tasks
+    /// and commands are given directly instead of being read from files.
+    pub fn in_memory(tasks: Vec<DeployTask>, commands: CommandSet) -> Config {
+        Config {
+            tasks: tasks.into_iter().map(|t| (t.name.clone(), t)).collect(),
+            commandpool: Some(commands),
+            ..Config::default()
+        }
+    }
+
+    /// The in-memory task called `name`, if any.
+    pub fn task(&self, name: &str) -> Option<&DeployTask> {
+        self.tasks.get(name)
+    }
+
+    /// Selects the profile overlaying this config, `None` keeps the plain one.
+    pub fn with_profile(mut self, profile: Option<String>) -> Result<Config, Error> {
+        if let Some(p) = &profile {
+            if !self.profiles.contains_key(p) {
+                return Err(Error::Failed(format!("No profile named `{}`!", p)));
+            }
+        }
+        self.profile = profile;
+        self.commandpool = None;
+        self.load_commandpool()?;
+        Ok(self)
+    }
+
+    pub fn profile_name(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    fn active_profile(&self) -> Option<&Profile> {
+        self.profile.as_ref().and_then(|p| self.profiles.get(p))
+    }
+
+    /// Variables of the config with the selected profile applied.
+    pub fn vars_in_use(&self) -> Vars {
+        let mut v = self.vars.clone();
+        if let Some(p) = self.active_profile() {
+            v.extend(p.vars.iter().map(|(k, x)| (k.clone(), x.clone())));
+        }
+        v
+    }
+
+    /// Environment for commands with the selected profile applied.
+    pub fn env_in_use(&self) -> HashMap<String, String> {
+        let mut v = self.env.clone();
+        if let Some(p) = self.active_profile() {
+            v.extend(p.env.iter().map(|(k, x)| (k.clone(), x.clone())));
+        }
+        v
+    }
+
+    /// The run history, relative to `config_dir`, if one is set.
+    pub fn history(&self) -> Option<History> {
+        if self.history_dir.is_empty() {
+            return None;
+        }
+        Some(History::new(PathBuf::from(&self.config_dir).join(&self.history_dir)))
+    }
+
+    /// The run log directory, relative to `config_dir`, if one is set.
+    pub fn log_dir(&self) -> Option<LogDir> {
+        if self.log_dir.is_empty() {
+            return None;
+        }
+        Some(LogDir::new(PathBuf::from(&self.config_dir).join(&self.log_dir), self.log_retention.clone()))
+    }
+
+    /// The run locks of this config, relative to `config_dir`, if
+    /// `lock_dir` is set.
+    pub fn locks(&self) -> Option<Locks> {
+        if self.lock_dir.is_empty() {
+            return None;
+        }
+        let scope = match (&self.lock_scope, &self.path) {
+            (s, _) if !s.is_empty() => s.clone(),
+            (_, Some(p)) => p.to_string_lossy().into_owned(),
+            (_, None) => String::new(),
+        };
+        Some(Locks::new(PathBuf::from(&self.config_dir).join(&self.lock_dir)).with_scope(&scope))
+    }
+
+    /// The audit log, relative to `config_dir`, if one is set.
+    pub fn audit_log(&self) -> Option<PathBuf> {
+        if self.audit_log.is_empty() {
+            return None;
+        }
+        Some(PathBuf::from(&self.config_dir).join(&self.audit_log))
+    }
+
+    /// SHA-256 of the config as loaded, with the selected profile and the
+    /// command pool, to tell which config a run used.
+    pub fn hash(&self) -> Result<String, Error> {
+        let v = serde_json::json!({
+            "config": self,
+            "profile": self.profile,
+            "commands": self.commandpool.as_ref().map(|c| c.as_commands()),
+        });
+        Ok(integrity::sha256(serde_json::to_string(&v)?.as_bytes()))
+    }
+
+    /// The policy in `policy_file`, relative to `config_dir`, if one is set.
+    pub fn policy(&self) -> Result<Option<Policy>, Error> {
+        if self.policy_file.is_empty() {
+            return Ok(None);
+        }
+        Policy::from_file(PathBuf::from(&self.config_dir).join(&self.policy_file)).map(Some)
+    }
+
+    pub fn vault_path(&self) -> PathBuf {
+        let f = if self.vault_file.is_empty() { VAULT_FILE } else { &self.vault_file };
+        PathBuf::from(&self.config_dir).join(f)
+    }
+
+    /// Reads the values of `secrets` and, if the vault exists, decrypts it
+    /// with `vault_key`. Secrets named in the config win over vault entries.
+    pub fn resolve_secrets(&self, vault_key: Option<&[u8]>) -> Result<Secrets, Error> {
+        let mut secrets = Secrets::resolve(&self.secrets, Path::new(&self.config_dir), |k| env::var(k).ok())?;
+        let path = self.vault_path();
+        if path.exists() {
+            let key = match vault_key {
+                Some(k) => k,
+                None => return Err(Error::Failed(format!("Vault `{}` needs a passphrase or key file!", path.display()))),
             };
+            for (name, value) in Vault::open(&path, key)?.entries() {
+                if !secrets.contains(name) {
+                    secrets.insert(name, value.clone(), None);
+                }
+            }
         }
-        self.commandpool.clone().unwrap()
+        Ok(secrets)
+    }
+
+    /// Variables the selected profile sets for the plan `name`.
+    pub fn plan_defaults(&self, name: &str) -> Vars {
+        self.active_profile()
+            .and_then(|p| p.plan_defaults.get(name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Every effective variable, environment variable, command and plan
+    /// default, tagged with whether the config or the profile set it.
+    pub fn describe(&mut self) -> Result<Vec<ConfigValue>, Error> {
+        fn sorted<V>(m: &HashMap<String, V>) -> Vec<(&String, &V)> {
+            let mut v: Vec<(&String, &V)> = m.iter().collect();
+            v.sort_by(|a, b| a.0.cmp(b.0));
+            v
+        }
+        let profile = self.active_profile().cloned().unwrap_or_default();
+        let profile_source = format!("profile `{}`", self.profile.clone().unwrap_or_default());
+        let source = |overridden: bool| if overridden { profile_source.clone() } else { "config".to_owned() };
+        let mut v: Vec<ConfigValue> = Vec::new();
+        for (k, x) in sorted(&self.vars_in_use()) {
+            v.push(ConfigValue { key: format!("vars.{}", k), value: x.clone(), source: source(profile.vars.contains_key(k)) });
+        }
+        for (k, x) in sorted(&self.env_in_use()) {
+            v.push(ConfigValue { key: format!("env.{}", k), value: x.clone(), source: source(profile.env.contains_key(k)) });
+        }
+        for c in self.get_commandpool()?.as_commands() {
+            v.push(ConfigValue {
+                key: format!("commands.{}", c.idx),
+                value: format!("{} {:?}", c.command, c.args),
+                source: source(profile.commands.iter().any(|p| p.idx == c.idx)),
+            });
+        }
+        for (k, x) in sorted(&self.secrets) {
+            v.push(ConfigValue { key: format!("secrets.{}", k), value: MASK.to_owned(), source: format!("config, from {}", x.describe()) });
+        }
+        for (plan, vars) in sorted(&profile.plan_defaults) {
+            for (k, x) in sorted(vars) {
+                v.push(ConfigValue { key: format!("plans.{}.{}", plan, k), value: x.clone(), source: source(true) });
+            }
+        }
+        Ok(v)
+    }
+
+    /// Reads one config file and everything it includes, `stack` holds the
+    /// files being loaded to catch include cycles and `loaded` the ones
+    /// already merged so a shared include is only read once.
+    fn load(path: &Path, stack: &mut Vec<PathBuf>, loaded: &mut Vec<PathBuf>, root: bool) -> Result<Config, Error> {
+        let canonical = fs::canonicalize(path)?;
+        if stack.contains(&canonical) {
+            return Err(Error::Failed(format!("Config `{}` includes itself!", path.display())));
+        }
+        let s = integrity::read(path)?;
+        let mut config: Config = serde_json::from_str(&s)?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        config.config_dir = match config.config_dir.as_str() {
+            "" | "." => base.to_string_lossy().into_owned(),
+            d => base.join(d).to_string_lossy().into_owned(),
+        };
+        if root {
+            config.apply_overrides(|k| env::var(k).ok())?;
+        }
+        config.expand_globs()?;
+
+        stack.push(canonical.clone());
+        loaded.push(canonical);
+        for pattern in config.include.clone() {
+            for p in config.glob(&pattern)? {
+                let p = PathBuf::from(&config.config_dir).join(p);
+                let c = fs::canonicalize(&p)?;
+                if loaded.contains(&c) && !stack.contains(&c) {
+                    continue;
+                }
+                let other = Self::load(&p, stack, loaded, false)?;
+                config.merge(other.into_absolute()?)?;
+            }
+        }
+        stack.pop();
+        Ok(config)
+    }
+
+    /// Replaces fields with `QUEENBEE_<FIELD>` values given by `lookup`. Lists
+    /// are comma separated and maps are `key=value` pairs, though both may
+    /// also be given as JSON. An overridden `config_dir` stays relative to
+    /// the current directory. Settings guarding the runs, `policy_file`,
+    /// `audit_log`, `lock_dir` and `global_lock`, only come from the config
+    /// file so the environment cannot turn them off.
+    fn apply_overrides<F: Fn(&str) -> Option<String>>(&mut self, lookup: F) -> Result<(), Error> {
+        fn list(s: &str) -> Vec<String> {
+            serde_json::from_str(s).unwrap_or_else(|_| {
+                s.split(',').map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect()
+            })
+        }
+        fn map(key: &str, s: &str) -> Result<HashMap<String, String>, Error> {
+            if let Ok(m) = serde_json::from_str(s) {
+                return Ok(m);
+            }
+            let mut m = HashMap::new();
+            for pair in s.split(',').filter(|x| !x.trim().is_empty()) {
+                match pair.find('=') {
+                    Some(i) => { m.insert(pair[..i].trim().to_owned(), pair[i + 1..].trim().to_owned()); },
+                    None => return Err(Error::Failed(format!("`{}` expects `key=value` pairs, got `{}`!", key, pair))),
+                }
+            }
+            Ok(m)
+        }
+        let var = |field: &str| {
+            let key = format!("QUEENBEE_{}", field.to_uppercase());
+            lookup(&key).map(|v| (key, v))
+        };
+        if let Some((_, v)) = var("config_dir") {
+            // The guarding settings keep pointing where the config file said.
+            for f in [&mut self.policy_file, &mut self.audit_log, &mut self.lock_dir] {
+                if !f.is_empty() {
+                    *f = Path::new(&self.config_dir).join(&*f).to_string_lossy().into_owned();
+                }
+            }
+            self.config_dir = v;
+        }
+        if let Some((_, v)) = var("command_config_file") { self.command_config_file = v; }
+        if let Some((_, v)) = var("command_config_files") { self.command_config_files = list(&v); }
+        if let Some((_, v)) = var("plan_files") { self.plan_files = list(&v); }
+        if let Some((k, v)) = var("taskfiles") { self.taskfiles = map(&k, &v)?; }
+        if let Some((_, v)) = var("task_globs") { self.task_globs = list(&v); }
+        if let Some((_, v)) = var("include") { self.include = list(&v); }
+        if let Some((k, v)) = var("vars") { self.vars = map(&k, &v)?; }
+        if let Some((k, v)) = var("env") { self.env = map(&k, &v)?; }
+        if let Some((_, v)) = var("history_dir") { self.history_dir = v; }
+        if let Some((_, v)) = var("log_dir") { self.log_dir = v; }
+        if let Some((_, v)) = var("log_retention") { self.log_retention = serde_json::from_str(&v)?; }
+        if let Some((_, v)) = var("lock_timeout_secs") { self.lock_timeout_secs = serde_json::from_str(&v)?; }
+        if let Some((_, v)) = var("vault_file") { self.vault_file = v; }
+        if let Some((_, v)) = var("secrets") { self.secrets = serde_json::from_str(&v)?; }
+        if let Some((_, v)) = var("schedules") { self.schedules = serde_json::from_str(&v)?; }
+        if let Some((_, v)) = var("profiles") { self.profiles = serde_json::from_str(&v)?; }
+        Ok(())
+    }
+
+    /// Files matching `pattern` relative to `config_dir`, or the pattern
+    /// itself when it has no wildcard.
+    fn glob(&self, pattern: &str) -> Result<Vec<String>, Error> {
+        if !pattern.contains(['*', '?', '[']) {
+            return Ok(vec![pattern.to_owned()]);
+        }
+        let dir = PathBuf::from(&self.config_dir);
+        let full = dir.join(pattern);
+        let paths = glob::glob(&full.to_string_lossy())
+            .map_err(|e| Error::Failed(format!("Invalid pattern `{}`: {}", pattern, e)))?;
+        let mut v: Vec<String> = Vec::new();
+        for p in paths {
+            let p = p.map_err(|e| Error::Io(e.into()))?;
+            // glob drops a leading `./`, so try the directory without it too.
+            let p = p.strip_prefix(&dir)
+                .or_else(|_| p.strip_prefix(dir.strip_prefix(".").unwrap_or(&dir)))
+                .unwrap_or(&p)
+                .to_path_buf();
+            v.push(p.to_string_lossy().into_owned());
+        }
+        v.sort();
+        Ok(v)
+    }
+
+    fn expand_globs(&mut self) -> Result<(), Error> {
+        let mut plan_files: Vec<String> = Vec::with_capacity(self.plan_files.len());
+        for p in &self.plan_files {
+            for f in self.glob(p)? {
+                if !plan_files.contains(&f) {
+                    plan_files.push(f);
+                }
+            }
+        }
+        self.plan_files = plan_files;
+
+        for pattern in std::mem::take(&mut self.task_globs) {
+            for f in self.glob(&pattern)? {
+                let path = PathBuf::from(&self.config_dir).join(&f);
+                let task = DeployTask::from_file(path.to_str().unwrap(), None)?;
+                self.add_taskfile(task.name, f)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_taskfile(&mut self, name: String, file: String) -> Result<(), Error> {
+        match self.taskfiles.get(&name) {
+            Some(f) if PathBuf::from(&self.config_dir).join(f) != PathBuf::from(&self.config_dir).join(&file) => {
+                Err(Error::Failed(format!("Task `{}` is defined in both `{}` and `{}`!", name, f, file)))
+            },
+            _ => {
+                self.taskfiles.insert(name, file);
+                Ok(())
+            },
+        }
+    }
+
+    /// Rewrites every file of an included config to an absolute path, so it
+    /// can be joined onto another `config_dir` without changing meaning.
+    fn into_absolute(mut self) -> Result<Config, Error> {
+        let dir = fs::canonicalize(if self.config_dir.is_empty() { "." } else { &self.config_dir })?;
+        let abs = |f: &String| dir.join(f).to_string_lossy().into_owned();
+        if !self.command_config_file.is_empty() {
+            self.command_config_files.insert(0, abs(&self.command_config_file));
+            self.command_config_file = String::new();
+        }
+        self.command_config_files = self.command_config_files.iter().map(abs).collect();
+        self.plan_files = self.plan_files.iter().map(abs).collect();
+        self.taskfiles = self.taskfiles.iter().map(|(k, v)| (k.clone(), abs(v))).collect();
+        for s in self.secrets.values_mut() {
+            s.file = s.file.as_ref().map(abs);
+        }
+        Ok(self)
+    }
+
+    fn merge(&mut self, other: Config) -> Result<(), Error> {
+        for f in other.command_config_files {
+            if !self.command_config_files.contains(&f) {
+                self.command_config_files.push(f);
+            }
+        }
+        for f in other.plan_files {
+            if !self.plan_files.contains(&f) {
+                self.plan_files.push(f);
+            }
+        }
+        for (name, f) in other.taskfiles {
+            self.add_taskfile(name, f)?;
+        }
+        for (k, v) in other.vars {
+            self.vars.entry(k).or_insert(v);
+        }
+        for (k, v) in other.env {
+            self.env.entry(k).or_insert(v);
+        }
+        for (k, v) in other.secrets {
+            self.secrets.entry(k).or_insert(v);
+        }
+        for s in other.schedules {
+            if !self.schedules.contains(&s) {
+                self.schedules.push(s);
+            }
+        }
+        for (name, p) in other.profiles {
+            if self.profiles.contains_key(&name) {
+                return Err(Error::Failed(format!("Profile `{}` is defined more than once!", name)));
+            }
+            self.profiles.insert(name, p);
+        }
+        Ok(())
+    }
+
+    pub fn get_commandpool(&mut self) -> Result<CommandSet, Error> {
+        self.load_commandpool()?;
+        Ok(self.commandpool.clone().unwrap())
     }
 
     fn load_commandpool(&mut self) -> Result<(), Error> {
         if self.commandpool.is_none() {
-            let path = PathBuf::from(&self.config_dir).join(&self.command_config_file);
-            self.commandpool = Some(CommandSet::from_file(path.to_str().unwrap())?);
+            let mut files: Vec<&String> = Vec::with_capacity(self.command_config_files.len() + 1);
+            if !self.command_config_file.is_empty() {
+                files.push(&self.command_config_file);
+            }
+            files.extend(self.command_config_files.iter());
+            let mut sets: Vec<(String, CommandSet)> = Vec::with_capacity(files.len());
+            for f in files {
+                let path = PathBuf::from(&self.config_dir).join(f);
+                sets.push((f.clone(), CommandSet::from_file(path.to_str().unwrap())?));
+            }
+            let mut pool = CommandSet::merge(sets)?;
+            if let Some(p) = self.active_profile() {
+                pool.replace(p.commands.clone());
+            }
+            self.commandpool = Some(pool);
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+static CONFIG_FILE: &str = "config.json";
+static VAULT_FILE: &str = "secrets.vault";
+
+fn is_default<T: Default + PartialEq>(t: &T) -> bool {
+    *t == T::default()
+}
+
+fn discover_from(explicit: Option<PathBuf>, cwd: &Path, xdg: Option<PathBuf>, etc: &Path) -> Option<PathBuf> {
+    if explicit.is_some() {
+        return explicit;
+    }
+    let mut candidates: Vec<PathBuf> = cwd.ancestors().map(|d| d.join(CONFIG_FILE)).collect();
+    if let Some(x) = xdg {
+        candidates.push(x.join("queenbee").join(CONFIG_FILE));
+    }
+    candidates.push(etc.join(CONFIG_FILE));
+    candidates.into_iter().find(|p| p.is_file())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, discover_from};
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_new() {
+        let config = Config::new(Some("./testresource/config.json".to_owned())).unwrap();
+        assert_eq!(config.plan_files, vec!["testplan.json".to_owned()]);
+        assert_eq!(config.taskfiles["Task1"], "testtasks.json");
+        assert_eq!(config.clone().get_commandpool().unwrap().as_commands().len(), 6);
+    }
+
+    #[test]
+    fn test_globs_and_includes() {
+        let mut config = Config::new(Some("./testresource/layout/config.json".to_owned())).unwrap();
+        assert_eq!(&config.plan_files[..2], &["plans/build.json".to_owned(), "plans/release.json".to_owned()]);
+        assert!(config.plan_files[2].ends_with("services/db/plan.json"));
+        assert_eq!(config.taskfiles["WebBuild"], "tasks/web/build.json");
+        assert!(config.taskfiles["DbMigrate"].ends_with("services/db/task.json"));
+        let idx: Vec<usize> = config.get_commandpool().unwrap().as_commands().iter().map(|c| c.idx).collect();
+        assert_eq!(idx, vec![1, 2, 10, 20]);
+    }
+
+    #[test]
+    fn test_profile() {
+        let config = Config::new(Some("./testresource/profileconfig.json".to_owned())).unwrap();
+        assert_eq!(config.vars_in_use()["region"], "eu");
+        assert_eq!(config.vars_in_use()["version"], "latest");
+        assert!(config.clone().with_profile(Some("qa".to_owned())).is_err());
+
+        let mut config = config.with_profile(Some("prod".to_owned())).unwrap();
+        assert_eq!(config.profile_name(), Some("prod"));
+        assert_eq!(config.vars_in_use()["region"], "eu");
+        assert_eq!(config.vars_in_use()["version"], "1.2.0");
+        assert_eq!(config.env_in_use()["RUST_LOG"], "warn");
+        assert_eq!(config.plan_defaults("Plan1")["replicas"], "3");
+        assert!(config.plan_defaults("Other").is_empty());
+        let pool = config.get_commandpool().unwrap().as_commands();
+        assert_eq!(pool[1].args, vec!["prod".to_owned()]);
+        assert_eq!(pool.len(), 6);
+
+        let shown = config.describe().unwrap();
+        let version = shown.iter().find(|v| v.key == "vars.version").unwrap();
+        assert_eq!(version.source, "profile `prod`");
+        let region = shown.iter().find(|v| v.key == "vars.region").unwrap();
+        assert_eq!(region.source, "config");
+        let cmd = shown.iter().find(|v| v.key == "commands.2").unwrap();
+        assert_eq!(cmd.source, "profile `prod`");
+    }
+
+    #[test]
+    fn test_config_dir_relative_to_file() {
+        let config = Config::new(Some("./testresource/layout/services/db/config.json".to_owned())).unwrap();
+        assert_eq!(PathBuf::from(&config.config_dir), PathBuf::from("./testresource/layout/services/db"));
+    }
+
+    #[test]
+    fn test_discover() {
+        let layout = Path::new("./testresource/layout");
+        let xdg = PathBuf::from("./testresource/xdg");
+        let explicit = Some(PathBuf::from("given.json"));
+        assert_eq!(discover_from(explicit.clone(), layout, None, layout), explicit);
+        // The closest parent with a config.json wins.
+        assert_eq!(discover_from(None, &layout.join("tasks/web"), None, layout), Some(layout.join("config.json")));
+        assert_eq!(discover_from(None, Path::new("/nonexistent/dir"), Some(xdg.clone()), layout), Some(xdg.join("queenbee/config.json")));
+        assert_eq!(discover_from(None, Path::new("/nonexistent/dir"), None, layout), Some(layout.join("config.json")));
+        assert_eq!(discover_from(None, Path::new("/nonexistent/dir"), None, Path::new("/nonexistent")), None);
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let mut config = Config::new(Some("./testresource/config.json".to_owned())).unwrap();
+        assert!(config.history().is_none());
+        let mut env: HashMap<&str, &str> = HashMap::new();
+        env.insert("QUEENBEE_PLAN_FILES", "a.json, b.json");
+        env.insert("QUEENBEE_TASKFILES", "T1=t1.json,T2=t2.json");
+        env.insert("QUEENBEE_VARS", r#"{"version": "2"}"#);
+        env.insert("QUEENBEE_COMMAND_CONFIG_FILE", "other.json");
+        env.insert("QUEENBEE_HISTORY_DIR", "runs");
+        env.insert("QUEENBEE_LOG_RETENTION", r#"{"keep_runs": 3}"#);
+        env.insert("QUEENBEE_POLICY_FILE", "");
+        env.insert("QUEENBEE_LOCK_DIR", "");
+        config.policy_file = "policy.json".to_owned();
+        config.lock_dir = "/run/queenbee/locks".to_owned();
+        config.apply_overrides(|k| env.get(k).map(|v| v.to_string())).unwrap();
+        assert_eq!((config.policy_file.as_str(), config.lock_dir.as_str()), ("policy.json", "/run/queenbee/locks"));
+        let mut moved = config.clone();
+        moved.apply_overrides(|k| if k == "QUEENBEE_CONFIG_DIR" { Some("/tmp".to_owned()) } else { None }).unwrap();
+        assert_eq!(moved.policy_file, "./testresource/policy.json");
+        assert_eq!(config.plan_files, vec!["a.json".to_owned(), "b.json".to_owned()]);
+        assert_eq!(config.taskfiles["T2"], "t2.json");
+        assert_eq!(config.vars["version"], "2");
+        assert_eq!(config.command_config_file, "other.json");
+        assert_eq!(config.history().unwrap().dir(), PathBuf::from("./testresource/runs"));
+        assert_eq!(config.log_retention.keep_runs, Some(3));
+        assert!(config.log_dir().is_none());
+
+        let bad = |k: &str| if k == "QUEENBEE_ENV" { Some("novalue".to_owned()) } else { None };
+        assert!(config.apply_overrides(bad).is_err());
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let e = Config::new(Some("./testresource/layout/cycle.json".to_owned()));
+        assert!(e.is_err());
+    }
+
+    #[test]
+    fn test_resolve_secrets() {
+        let config = Config::new(Some("./testresource/secret/config.json".to_owned())).unwrap();
+        assert_eq!(config.vault_path(), PathBuf::from("./testresource/secret/secrets.vault"));
+        let secrets = config.resolve_secrets(None).unwrap();
+        assert_eq!(secrets.vars()["secrets.token"], "s3cr3t-token");
+        assert_eq!(secrets.env()["API_TOKEN"], "s3cr3t-token");
+
+        let mut config = config;
+        config.vault_file = "../secret.txt".to_owned();
+        assert!(config.resolve_secrets(None).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use crate::executor::Executor;
+use chrono::{DateTime, Local, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Longest sleep between two looks at the clock and the signals.
+const TICK: Duration = Duration::from_millis(500);
+
+/// When `queenbee daemon` runs a plan, from the config's `schedules`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub struct ScheduleEntry {
+    pub plan: String,
+    /// Crontab expression `min hour day month weekday`, optionally with
+    /// seconds before and a year after.
+    pub cron: String,
+    /// IANA name of the time zone `cron` is in, like `Europe/Berlin`, the
+    /// local time if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+/// A checked `ScheduleEntry`.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub plan: String,
+    cron: cron::Schedule,
+    timezone: Option<Tz>,
+}
+
+impl Schedule {
+    pub fn new(entry: &ScheduleEntry) -> Result<Schedule, Error> {
+        let fields = entry.cron.split_whitespace().count();
+        let expr = if fields == 5 { format!("0 {}", entry.cron) } else { entry.cron.clone() };
+        let cron = cron::Schedule::from_str(&expr)
+            .map_err(|e| Error::Failed(format!("Bad cron expression `{}` for plan `{}`: {}", entry.cron, entry.plan, e)))?;
+        let timezone = match &entry.timezone {
+            Some(tz) => Some(tz.parse::<Tz>()
+                .map_err(|_| Error::Failed(format!("Unknown time zone `{}` for plan `{}`!", tz, entry.plan)))?),
+            None => None,
+        };
+        Ok(Schedule { plan: entry.plan.clone(), cron, timezone })
+    }
+
+    /// The first time after `t` the plan is due, none if never again.
+    pub fn next_after(&self, t: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        fn next<Z: TimeZone>(cron: &cron::Schedule, t: DateTime<Z>) -> Option<DateTime<Utc>> {
+            cron.after(&t).next().map(|d| d.with_timezone(&Utc))
+        }
+        match self.timezone {
+            Some(tz) => next(&self.cron, t.with_timezone(&tz)),
+            None => next(&self.cron, t.with_timezone(&Local)),
+        }
+    }
+}
+
+/// Runs the plans of a config on their schedules until stopped. Each
+/// scheduled plan gets an executor of its own, so different plans may run
+/// at the same time, but a plan still running when it is due again is
+/// skipped. Finished runs are recorded in the config's history.
+pub struct Daemon<F: Fn() -> Executor> {
+    new_executor: F,
+    config_path: Option<String>,
+    profile: Option<String>,
+    executors: HashMap<String, Arc<Mutex<Executor>>>,
+    /// Every schedule with the next time it is due.
+    schedules: Vec<(Schedule, Option<DateTime<Utc>>)>,
+    running: Arc<Mutex<HashSet<String>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<F: Fn() -> Executor> Daemon<F> {
+    /// Loads the config at `config_path` with `profile` into executors made
+    /// by `new_executor`, which adds the observers and keys they need.
+    pub fn new(new_executor: F, config_path: Option<String>, profile: Option<String>) -> Result<Self, Error> {
+        let mut d = Daemon {
+            new_executor,
+            config_path,
+            profile,
+            executors: HashMap::new(),
+            schedules: Vec::new(),
+            running: Arc::new(Mutex::new(HashSet::new())),
+            workers: Vec::new(),
+        };
+        d.reload_at(Utc::now())?;
+        Ok(d)
+    }
+
+    /// Loads the config again. Nothing changes if it fails, runs already
+    /// going keep the config they started with.
+    pub fn reload(&mut self) -> Result<(), Error> {
+        self.reload_at(Utc::now())
+    }
+
+    fn reload_at(&mut self, now: DateTime<Utc>) -> Result<(), Error> {
+        let load = || -> Result<Executor, Error> {
+            let mut e = (self.new_executor)();
+            e.load_context(self.config_path.clone(), self.profile.clone())?;
+            Ok(e)
+        };
+        let first = load()?;
+        let entries = first.config().map(|c| c.schedules.clone()).unwrap_or_default();
+        let plans = first.plan_names();
+        let mut schedules = Vec::with_capacity(entries.len());
+        for e in &entries {
+            if !plans.contains(&e.plan) {
+                return Err(Error::Failed(format!("Schedule for unknown plan `{}`!", e.plan)));
+            }
+            let s = Schedule::new(e)?;
+            let next = s.next_after(&now);
+            schedules.push((s, next));
+        }
+        let mut executors = HashMap::new();
+        let mut first = Some(first);
+        for (s, _) in &schedules {
+            if !executors.contains_key(&s.plan) {
+                let e = match first.take() {
+                    Some(e) => e,
+                    None => load()?,
+                };
+                executors.insert(s.plan.clone(), Arc::new(Mutex::new(e)));
+            }
+        }
+        self.executors = executors;
+        self.schedules = schedules;
+        Ok(())
+    }
+
+    /// Plans and when they are due next, in schedule order.
+    pub fn upcoming(&self) -> Vec<(&str, Option<DateTime<Utc>>)> {
+        self.schedules.iter().map(|(s, t)| (s.plan.as_str(), *t)).collect()
+    }
+
+    /// Starts every plan due at `now` which is not running yet, giving
+    /// their names.
+    pub fn run_due(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let mut started = Vec::new();
+        for i in 0..self.schedules.len() {
+            let (schedule, next) = &mut self.schedules[i];
+            if !next.is_some_and(|t| t <= now) {
+                continue;
+            }
+            *next = schedule.next_after(&now);
+            let plan = schedule.plan.clone();
+            if !self.running.lock().unwrap().insert(plan.clone()) {
+                log(&format!("Plan `{}` is still running, skipping this run", plan));
+                continue;
+            }
+            let executor = self.executors[&plan].clone();
+            let running = self.running.clone();
+            log(&format!("Starting plan `{}`", plan));
+            started.push(plan.clone());
+            self.workers.push(thread::spawn(move || {
+                run_plan(&mut executor.lock().unwrap(), &plan);
+                running.lock().unwrap().remove(&plan);
+            }));
+        }
+        started
+    }
+
+    /// Runs the schedules until SIGTERM or SIGINT, loading the config again
+    /// on SIGHUP, then waits for the runs still going.
+    pub fn run(mut self) -> Result<(), Error> {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+        let hup = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGHUP, hup.clone())?;
+        signal_hook::flag::register(SIGTERM, stop.clone())?;
+        signal_hook::flag::register(SIGINT, stop.clone())?;
+        self.serve(&hup, &stop);
+        Ok(())
+    }
+
+    fn serve(&mut self, hup: &AtomicBool, stop: &AtomicBool) {
+        for (plan, next) in self.upcoming() {
+            log(&format!("Plan `{}` next due {}", plan, describe(next)));
+        }
+        while !stop.load(Ordering::Relaxed) {
+            if hup.swap(false, Ordering::Relaxed) {
+                match self.reload() {
+                    Ok(()) => log(&format!("Config reloaded, {} schedules", self.schedules.len())),
+                    Err(e) => log(&format!("Error reloading config, keeping the old one: {}", e)),
+                }
+            }
+            let now = Utc::now();
+            self.run_due(now);
+            self.workers.retain(|w| !w.is_finished());
+            let wait = self.schedules.iter()
+                .filter_map(|(_, t)| *t)
+                .min()
+                .and_then(|t| (t - Utc::now()).to_std().ok())
+                .map_or(TICK, |d| d.min(TICK));
+            thread::sleep(wait);
+        }
+        if !self.workers.is_empty() {
+            log(&format!("Stopping, waiting for {} runs", self.workers.len()));
+        }
+        self.wait();
+    }
+
+    /// Waits for the runs started so far.
+    pub fn wait(&mut self) {
+        for w in self.workers.drain(..) {
+            let _ = w.join();
+        }
+    }
+}
+
+/// Runs `plan` and records it, problems only end up in the log.
+fn run_plan(executor: &mut Executor, plan: &str) {
+    match executor.run_deploy_plan(plan) {
+        Ok(report) => {
+            match &report.error {
+                Some(e) => log(&format!("Plan `{}` failed in run `{}`: {}", plan, report.id, e)),
+                None => log(&format!("Plan `{}` finished in run `{}`", plan, report.id)),
+            }
+            if let Some(h) = executor.config().and_then(|c| c.history()) {
+                if let Err(e) = h.record(&report) {
+                    log(&format!("Error recording run `{}`: {}", report.id, e));
+                }
+            }
+        },
+        Err(e) => log(&format!("Plan `{}` did not run: {}", plan, e)),
+    }
+}
+
+fn describe(t: Option<DateTime<Utc>>) -> String {
+    match t {
+        Some(t) => DateTime::<Local>::from(t).format("%Y-%m-%d %H:%M:%S %Z").to_string(),
+        None => "never".to_owned(),
+    }
+}
+
+fn log(msg: &str) {
+    eprintln!("[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), msg);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Daemon, Schedule, ScheduleEntry};
+    use crate::executor::Executor;
+    use crate::history::History;
+    use crate::testutil::TempDir;
+    use chrono::{DateTime, Duration, Utc};
+    use std::fs;
+
+    fn entry(cron: &str, timezone: Option<&str>) -> ScheduleEntry {
+        ScheduleEntry { plan: "nightly".to_owned(), cron: cron.to_owned(), timezone: timezone.map(|s| s.to_owned()) }
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_schedule() {
+        let s = Schedule::new(&entry("30 2 * * *", Some("Europe/Berlin"))).unwrap();
+        // Summer time, 02:30 in Berlin is 00:30 UTC.
+        assert_eq!(s.next_after(&utc("2026-07-01T12:00:00Z")), Some(utc("2026-07-02T00:30:00Z")));
+        // Winter time, 01:30 UTC.
+        assert_eq!(s.next_after(&utc("2026-01-01T12:00:00Z")), Some(utc("2026-01-02T01:30:00Z")));
+
+        let s = Schedule::new(&entry("*/10 * * * * *", Some("UTC"))).unwrap();
+        assert_eq!(s.next_after(&utc("2026-01-01T00:00:05Z")), Some(utc("2026-01-01T00:00:10Z")));
+        assert_eq!(s.next_after(&utc("2026-01-01T00:00:10Z")), Some(utc("2026-01-01T00:00:20Z")));
+
+        assert!(Schedule::new(&entry("61 * * * *", None)).is_err());
+        assert!(Schedule::new(&entry("* * * * *", Some("Mars/Olympus"))).is_err());
+    }
+
+    #[test]
+    fn test_daemon() {
+        let dir = TempDir::new("daemon");
+        let config = dir.join("config.json");
+        let write_config = |cron: &str| {
+            fs::write(&config, format!(r#"{{
+                "command_config_file": "commands.json",
+                "plan_files": ["plan.json"],
+                "taskfiles": {{ "slow": "task.json" }},
+                "history_dir": "runs",
+                "lock_dir": "locks",
+                "schedules": [{{ "plan": "nightly", "cron": "{}", "timezone": "UTC" }}]
+            }}"#, cron)).unwrap();
+        };
+        write_config("0 3 * * *");
+        fs::write(dir.join("commands.json"), r#"[{ "idx": 1, "command": "sleep", "args": ["1"] }]"#).unwrap();
+        fs::write(dir.join("task.json"), r#"{ "name": "slow", "commandseq": [1] }"#).unwrap();
+        fs::write(dir.join("plan.json"), r#"{ "name": "nightly", "tasknames": ["slow"] }"#).unwrap();
+
+        let mut d = Daemon::new(Executor::new, Some(config.to_string_lossy().into_owned()), None).unwrap();
+        let next = d.upcoming()[0].1.unwrap();
+        assert!(d.run_due(next - Duration::seconds(1)).is_empty());
+        assert_eq!(d.run_due(next), vec!["nightly"]);
+        // Still sleeping when due again.
+        let next = d.upcoming()[0].1.unwrap();
+        assert!(d.run_due(next).is_empty());
+        d.wait();
+        assert_eq!(History::new(dir.join("runs")).list().unwrap().len(), 1);
+
+        write_config("not cron");
+        assert!(d.reload().is_err());
+        assert_eq!(d.upcoming().len(), 1);
+        write_config("0 3 * * *");
+        d.reload().unwrap();
+    }
+}
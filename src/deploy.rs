@@ -23,17 +23,19 @@
 pub mod command;
 pub mod task;
 pub mod plan;
+pub mod expect;
+pub mod template;
+pub mod builder;
 
 extern crate serde;
 extern crate serde_json;
 
 use std::process::Command;
 use std::fs::File;
-use std::io::{Read};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::cmp::{PartialOrd, Ord};
 use serde::{Serialize, Deserialize};
 
 pub use command::{ExecutableCommand, CommandSet};
 pub use task::DeployTask;
-pub use plan::DeployPlan;
\ No newline at end of file
+pub use plan::{DeployPlan, PlanStep};
\ No newline at end of file
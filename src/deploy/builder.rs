@@ -0,0 +1,419 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use super::{
+    File,
+    PathBuf,
+    command::{CommandSet, ExecutableCommand},
+    expect::Expectation,
+    plan::{check_plan_graph, DeployPlan, PlanStep},
+    task::{DeployTask, TaskParam},
+    template::Vars,
+};
+use crate::Error;
+use crate::config::Config;
+use crate::limits::Limits;
+use crate::sandbox::Sandbox;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+
+/// Builds a `DeployTask` together with the commands it runs.
+///
+/// ```
+/// use queenbee::deploy::builder::{PlanBuilder, TaskBuilder};
+///
+/// let bundle = PlanBuilder::new("release")
+///     .task(TaskBuilder::new("build").cmd("cargo", ["build", "--release"]))
+///     .build()
+///     .unwrap();
+/// assert_eq!(bundle.tasks[0].commandseq, vec![1]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TaskBuilder {
+    task: DeployTask,
+    commands: Vec<ExecutableCommand>,
+}
+
+impl TaskBuilder {
+    pub fn new(name: &str) -> Self {
+        TaskBuilder {
+            task: DeployTask::new(name, Vec::new()),
+            commands: Vec::new(),
+        }
+    }
+
+    /// Appends a command, its index in the pool is assigned by `build`.
+    pub fn cmd<I, S>(self, command: &str, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.command(ExecutableCommand {
+            idx: 0,
+            command: command.to_owned(),
+            args: args.into_iter().map(Into::into).collect(),
+            expect: None,
+            with_items: Vec::new(),
+            user: None,
+            group: None,
+            limits: None,
+            sandbox: None,
+        })
+    }
+
+    /// Appends a fully described command, its `idx` is replaced by `build`.
+    pub fn command(mut self, c: ExecutableCommand) -> Self {
+        self.commands.push(c);
+        self
+    }
+
+    /// Sets the expectation of the last added command.
+    pub fn expect(mut self, e: Expectation) -> Self {
+        if let Some(c) = self.commands.last_mut() {
+            c.expect = Some(e);
+        }
+        self
+    }
+
+    /// Sets the resource limits of the last added command.
+    pub fn limits(mut self, l: Limits) -> Self {
+        if let Some(c) = self.commands.last_mut() {
+            c.limits = Some(l);
+        }
+        self
+    }
+
+    /// Runs the last added command in `sandbox`.
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        if let Some(c) = self.commands.last_mut() {
+            c.sandbox = Some(sandbox);
+        }
+        self
+    }
+
+    pub fn param(mut self, name: &str, p: TaskParam) -> Self {
+        self.task.params.insert(name.to_owned(), p);
+        self
+    }
+
+    pub fn with_items<I, S>(mut self, items: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.task.with_items = items.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn matrix<I, S>(mut self, key: &str, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.task.matrix.insert(key.to_owned(), values.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn parallel(mut self, n: usize) -> Self {
+        self.task.parallel = Some(n);
+        self
+    }
+
+    /// Runs the task's commands as `user`, unless a command sets its own.
+    pub fn user(mut self, user: &str) -> Self {
+        self.task.user = Some(user.to_owned());
+        self
+    }
+
+    /// Runs the task's commands with `group`, unless a command sets its own.
+    pub fn group(mut self, group: &str) -> Self {
+        self.task.group = Some(group.to_owned());
+        self
+    }
+}
+
+/// Builds a `DeployPlan` out of tasks and nested plans, see `TaskBuilder`.
+#[derive(Debug, Clone)]
+pub struct PlanBuilder {
+    name: String,
+    steps: Vec<PlanStep>,
+    vars: Vars,
+    tasks: Vec<TaskBuilder>,
+    plans: Vec<PlanBuilder>,
+}
+
+impl PlanBuilder {
+    pub fn new(name: &str) -> Self {
+        PlanBuilder {
+            name: name.to_owned(),
+            steps: Vec::new(),
+            vars: Vars::new(),
+            tasks: Vec::new(),
+            plans: Vec::new(),
+        }
+    }
+
+    pub fn task(self, t: TaskBuilder) -> Self {
+        self.task_with_args(t, BTreeMap::new())
+    }
+
+    /// Adds a task step with values for the task's parameters.
+    pub fn task_with_args(mut self, t: TaskBuilder, args: BTreeMap<String, Value>) -> Self {
+        self.steps.push(PlanStep::Task { task: t.task.name.clone(), args });
+        self.tasks.push(t);
+        self
+    }
+
+    /// Adds a nested plan step, `vars` are passed down to it.
+    pub fn plan(mut self, p: PlanBuilder, vars: Vars) -> Self {
+        self.steps.push(PlanStep::Plan { plan: p.name.clone(), vars });
+        self.plans.push(p);
+        self
+    }
+
+    /// Adds a step referring to a task or plan added elsewhere by name.
+    pub fn step(mut self, s: PlanStep) -> Self {
+        self.steps.push(s);
+        self
+    }
+
+    pub fn var(mut self, key: &str, value: &str) -> Self {
+        self.vars.insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    /// Assigns command indexes and checks the result the same way files
+    /// are checked when loaded.
+    pub fn build(self) -> Result<PlanBundle, Error> {
+        let mut bundle = PlanBundle {
+            plans: Vec::new(),
+            tasks: Vec::new(),
+            commands: CommandSet::default(),
+        };
+        let mut commands: Vec<ExecutableCommand> = Vec::new();
+        self.collect(&mut bundle, &mut commands)?;
+        bundle.commands = CommandSet::new(commands);
+        bundle.validate()?;
+        Ok(bundle)
+    }
+
+    fn collect(self, bundle: &mut PlanBundle, commands: &mut Vec<ExecutableCommand>) -> Result<(), Error> {
+        if bundle.plans.iter().any(|p| p.name == self.name) {
+            return Err(Error::Failed(format!("Plan `{}` is defined more than once!", &self.name)));
+        }
+        bundle.plans.push(DeployPlan::new(&self.name, self.steps, self.vars));
+
+        for tb in self.tasks {
+            let mut task = tb.task;
+            match bundle.tasks.iter().find(|t| t.name == task.name) {
+                // The same builder used in two steps, keep the first one.
+                Some(t) if same_task(t, commands, &task, &tb.commands) => continue,
+                Some(_) => return Err(Error::Failed(format!("Task `{}` is defined more than once!", &task.name))),
+                None => {},
+            }
+            for mut c in tb.commands {
+                c.idx = commands.len() + 1;
+                task.commandseq.push(c.idx);
+                commands.push(c);
+            }
+            bundle.tasks.push(task);
+        }
+        for p in self.plans {
+            p.collect(bundle, commands)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether task `a`, with its commands in `pool`, and task `b` with
+/// `b_commands` are alike but for the command indexes.
+fn same_task(a: &DeployTask, pool: &[ExecutableCommand], b: &DeployTask, b_commands: &[ExecutableCommand]) -> bool {
+    let unnumbered = |c: &ExecutableCommand| ExecutableCommand { idx: 0, ..c.clone() };
+    let a_commands: Vec<ExecutableCommand> = a.commandseq.iter().map(|i| unnumbered(&pool[i - 1])).collect();
+    let b_commands: Vec<ExecutableCommand> = b_commands.iter().map(unnumbered).collect();
+    let (mut a, mut b) = (a.clone(), b.clone());
+    a.commandseq.clear();
+    b.commandseq.clear();
+    a == b && a_commands == b_commands
+}
+
+/// Plans, tasks and commands built in code, ready for `Executor::load_bundle`
+/// or to be written out as regular config files. The first plan is the one
+/// the builder was started with.
+#[derive(Debug, Clone)]
+pub struct PlanBundle {
+    pub plans: Vec<DeployPlan>,
+    pub tasks: Vec<DeployTask>,
+    pub commands: CommandSet,
+}
+
+impl PlanBundle {
+    /// Same checks as loading from files: expectations, parameter defaults,
+    /// nested plans and task arguments.
+    pub fn validate(&self) -> Result<(), Error> {
+        for c in self.commands.as_commands() {
+            c.validate()?;
+        }
+        for t in &self.tasks {
+            t.validate()?;
+        }
+        let plans: HashMap<String, DeployPlan> = self.plans_with_config().into_iter().map(|p| (p.name.clone(), p)).collect();
+        check_plan_graph(&plans)?;
+        for p in plans.values() {
+            p.validate()?;
+        }
+        Ok(())
+    }
+
+    /// The in-memory config the plans run with.
+    pub fn config(&self) -> Config {
+        Config::in_memory(self.tasks.clone(), self.commands.clone())
+    }
+
+    /// Plans with the in-memory config attached, ready to run.
+    pub fn plans_with_config(&self) -> Vec<DeployPlan> {
+        let config = self.config();
+        self.plans.iter().cloned().map(|mut p| {
+            p.set_config(Some(config.clone()));
+            p
+        }).collect()
+    }
+
+    /// Writes `config.json`, `commands.json`, `plans/<name>.json` and
+    /// `tasks/<name>.json` into `dir`, loadable with `Config::new`. Names
+    /// which are not plain file names are refused.
+    pub fn write_to_dir(&self, dir: &str) -> Result<(), Error> {
+        for name in self.tasks.iter().map(|t| &t.name).chain(self.plans.iter().map(|p| &p.name)) {
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+                return Err(Error::Failed(format!("`{}` cannot be used as a file name!", name)));
+            }
+        }
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(dir.join("plans"))?;
+        fs::create_dir_all(dir.join("tasks"))?;
+        let mut config = Config::default();
+        config.command_config_file = "commands.json".to_owned();
+        write_json(&dir.join(&config.command_config_file), &self.commands)?;
+        for t in &self.tasks {
+            let f = format!("tasks/{}.json", t.name);
+            write_json(&dir.join(&f), t)?;
+            config.taskfiles.insert(t.name.clone(), f);
+        }
+        for p in &self.plans {
+            let f = format!("plans/{}.json", p.name);
+            write_json(&dir.join(&f), p)?;
+            config.plan_files.push(f);
+        }
+        write_json(&dir.join("config.json"), &config)
+    }
+}
+
+fn write_json<T: Serialize>(path: &PathBuf, v: &T) -> Result<(), Error> {
+    let mut f = File::create(path)?;
+    f.write_all(serde_json::to_string_pretty(v)?.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PlanBuilder, TaskBuilder};
+    use crate::config::Config;
+    use crate::deploy::expect::Expectation;
+    use crate::deploy::task::{ParamType, TaskParam};
+    use crate::deploy::template::Vars;
+    use crate::testutil::TempDir;
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+
+    fn release() -> PlanBuilder {
+        let build = TaskBuilder::new("build")
+            .cmd("echo", ["building", "{{ service }}"])
+            .expect(Expectation { stdout_contains: vec!["building".to_owned()], ..Expectation::default() })
+            .param("service", TaskParam { kind: ParamType::String, required: true, default: None });
+        let mut args: BTreeMap<String, Value> = BTreeMap::new();
+        args.insert("service".to_owned(), Value::from("web"));
+        let migrate = PlanBuilder::new("migrate")
+            .task(TaskBuilder::new("migrate").cmd("echo", ["migrating", "{{ version }}"]));
+        let mut vars = Vars::new();
+        vars.insert("version".to_owned(), "{{ version }}".to_owned());
+        PlanBuilder::new("release")
+            .var("version", "1.0")
+            .task_with_args(build, args)
+            .plan(migrate, vars)
+    }
+
+    #[test]
+    fn test_build() {
+        let bundle = release().build().unwrap();
+        assert_eq!(bundle.plans.len(), 2);
+        assert_eq!(bundle.plans[0].name, "release");
+        assert_eq!(bundle.tasks[0].commandseq, vec![1]);
+        assert_eq!(bundle.tasks[1].commandseq, vec![2]);
+        assert_eq!(bundle.commands.as_commands()[1].command, "echo");
+    }
+
+    #[test]
+    fn test_build_validates() {
+        let missing_args = PlanBuilder::new("p")
+            .task(TaskBuilder::new("t").cmd("true", Vec::<String>::new())
+                .param("x", TaskParam { required: true, ..TaskParam::default() }));
+        assert!(missing_args.build().is_err());
+        let bad_regex = PlanBuilder::new("p")
+            .task(TaskBuilder::new("t").cmd("true", Vec::<String>::new())
+                .expect(Expectation { stdout_matches: vec!["(".to_owned()], ..Expectation::default() }));
+        assert!(bad_regex.build().is_err());
+        let clash = PlanBuilder::new("p")
+            .task(TaskBuilder::new("t").cmd("true", Vec::<String>::new()))
+            .task(TaskBuilder::new("t").cmd("false", Vec::<String>::new()));
+        assert!(clash.build().is_err());
+        let reused = TaskBuilder::new("t").cmd("true", Vec::<String>::new());
+        let twice = PlanBuilder::new("p").task(reused.clone()).task(reused).build().unwrap();
+        assert_eq!(twice.tasks.len(), 1);
+        assert_eq!(twice.commands.as_commands().len(), 1);
+        let expect = Expectation { exit_code: vec![1], ..Expectation::default() };
+        let differs = PlanBuilder::new("p")
+            .task(TaskBuilder::new("t").cmd("true", Vec::<String>::new()))
+            .task(TaskBuilder::new("t").cmd("true", Vec::<String>::new()).expect(expect));
+        assert!(differs.build().is_err());
+    }
+
+    #[test]
+    fn test_write_to_dir() {
+        let tmp = TempDir::new("builder");
+        let dir = tmp.join("bundle");
+        let bundle = release().build().unwrap();
+        bundle.write_to_dir(dir.to_str().unwrap()).unwrap();
+        let mut config = Config::new(Some(dir.join("config.json").to_string_lossy().into_owned())).unwrap();
+        assert_eq!(config.plan_files, vec!["plans/release.json".to_owned(), "plans/migrate.json".to_owned()]);
+        assert_eq!(config.taskfiles["build"], "tasks/build.json");
+        assert_eq!(config.get_commandpool().unwrap(), bundle.commands);
+
+        let dir = tmp.join("escape");
+        let escape = PlanBuilder::new("../../escape").task(TaskBuilder::new("t").cmd("true", Vec::<String>::new()));
+        let e = escape.build().unwrap().write_to_dir(dir.to_str().unwrap()).unwrap_err();
+        assert_eq!(e.to_string(), "`../../escape` cannot be used as a file name!");
+        assert!(!dir.exists());
+    }
+}
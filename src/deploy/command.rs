@@ -26,13 +26,17 @@ use super::{
     PartialOrd,
     Ord,
     Command,
-    File,
-    Read,
-    PathBuf
+    Path,
 };
+use super::expect::Expectation;
+use super::template::{self, Vars};
 use crate::Error;
-use serde_json;
+use crate::identity::Identity;
+use crate::limits::Limits;
+use crate::sandbox::Sandbox;
+use crate::integrity;
 use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
 
 
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
@@ -40,13 +44,102 @@ pub struct ExecutableCommand {
     pub idx: usize,
     pub command: String,
     pub args: Vec<String>,
+    /// What the command must do to succeed, exit with 0 if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expect: Option<Expectation>,
+    /// Runs the command once per item, exposed to templates as `{{ item }}`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub with_items: Vec<String>,
+    /// User to run as, by name or uid, the task's or plan's if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Group to run as, by name or gid, the user's primary group if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Resource limits and priority of the command's process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<Limits>,
+    /// Runs the command in new namespaces with a read-only filesystem.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<Sandbox>,
 }
 
 impl ExecutableCommand {
     pub fn as_command(&self) -> Command {
         let mut c = Command::new(&self.command);
         c.args(&self.args);
-        return c;
+        c
+    }
+
+    /// Checks everything which can be checked before running: the
+    /// expectations, the limits, the sandbox and that the user and group
+    /// exist.
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(e) = &self.expect {
+            e.validate()?;
+        }
+        if let Some(l) = &self.limits {
+            l.validate()?;
+        }
+        if let Some(s) = &self.sandbox {
+            s.validate()?;
+        }
+        Identity::resolve(self.user.as_deref(), self.group.as_deref())?;
+        Ok(())
+    }
+
+    /// The ids to run as, checking queenbee is allowed to switch to them.
+    pub fn identity(&self) -> Result<Option<Identity>, Error> {
+        let id = Identity::resolve(self.user.as_deref(), self.group.as_deref())?;
+        if let Some(id) = &id {
+            id.check_privileges()?;
+        }
+        Ok(id)
+    }
+
+    /// Checks the placeholders of the command and its items against the
+    /// variables in `known`.
+    pub fn check_vars(&self, known: &BTreeSet<String>) -> Result<(), Error> {
+        for i in &self.with_items {
+            template::check(i, known)?;
+        }
+        let mut known = known.clone();
+        if !self.with_items.is_empty() {
+            known.insert("item".to_owned());
+        }
+        template::check(&self.command, &known)?;
+        for a in &self.args {
+            template::check(a, &known)?;
+        }
+        Ok(())
+    }
+
+    /// Expands `with_items` and fills in template placeholders, returning the
+    /// concrete commands to execute in order.
+    pub fn expand(&self, vars: &Vars) -> Result<Vec<ExecutableCommand>, Error> {
+        if self.with_items.is_empty() {
+            return Ok(vec![self.render(vars)?]);
+        }
+        let mut v = Vec::with_capacity(self.with_items.len());
+        for item in &self.with_items {
+            let mut vars = vars.clone();
+            vars.insert("item".to_owned(), template::render(item, &vars)?);
+            v.push(self.render(&vars)?);
+        }
+        Ok(v)
+    }
+
+    fn render(&self, vars: &Vars) -> Result<ExecutableCommand, Error> {
+        let mut args = Vec::with_capacity(self.args.len());
+        for a in &self.args {
+            args.push(template::render(a, vars)?);
+        }
+        Ok(ExecutableCommand {
+            command: template::render(&self.command, vars)?,
+            args,
+            with_items: Vec::new(),
+            ..self.clone()
+        })
     }
 }
 
@@ -62,7 +155,8 @@ impl Ord for ExecutableCommand {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(transparent)]
 pub struct CommandSet {
     commands: Vec<ExecutableCommand>,
 }
@@ -77,32 +171,68 @@ impl CommandSet {
     pub fn from_file(s: &str) -> Result<Self, Error> {
         let commands = Self::load_from_file(s.to_owned())?;
         Ok(CommandSet { 
-            commands: commands
+            commands
         })
     }
 
-    pub fn select_command_by_index(&self, index: &[usize]) -> Option<Self> {
+    /// Joins command pools loaded from several files, each paired with its
+    /// file name for error messages. The same index may only appear twice if
+    /// both definitions are identical.
+    pub fn merge(sets: Vec<(String, CommandSet)>) -> Result<Self, Error> {
+        let mut seen: HashMap<usize, (String, ExecutableCommand)> = HashMap::new();
+        for (origin, set) in sets {
+            for c in set.commands {
+                match seen.get(&c.idx) {
+                    Some((o, existing)) if *existing != c => {
+                        return Err(Error::Failed(format!("Command index `{}` is defined differently in `{}` and `{}`!", c.idx, o, origin)));
+                    },
+                    Some(_) => {},
+                    None => { seen.insert(c.idx, (origin.clone(), c)); },
+                }
+            }
+        }
+        let mut commands: Vec<ExecutableCommand> = seen.into_iter().map(|(_, (_, c))| c).collect();
+        commands.sort();
+        Ok(Self::new(commands))
+    }
+
+    /// Puts `commands` in place of the ones with the same `idx`, appending
+    /// those which are new.
+    pub fn replace(&mut self, commands: Vec<ExecutableCommand>) {
+        for c in commands {
+            match self.commands.iter_mut().find(|x| x.idx == c.idx) {
+                Some(x) => *x = c,
+                None => self.commands.push(c),
+            }
+        }
+        self.commands.sort();
+    }
+
+    /// The commands with the `idx` values in `index`, in that order. Fails
+    /// on an index no command in the set has.
+    pub fn select_command_by_index(&self, index: &[usize]) -> Result<Option<Self>, Error> {
         let mut v: Vec<ExecutableCommand> = Vec::with_capacity(index.len());
         for i in index {
-            if i - 1 >= self.commands.len() {
-                panic!("Index out of bounds, no such command with index `{}` in current commandset!");
-            } 
-            v.push(self.commands[i - 1].clone());
+            match self.commands.iter().find(|c| c.idx == *i) {
+                Some(c) => v.push(c.clone()),
+                None => return Err(Error::Failed(format!("No command with index `{}` in the command pool!", i))),
+            }
         }
-        if v.len() == 0 {
-            None
+        if v.is_empty() {
+            Ok(None)
         } else {
-            Some(Self::new(v))
+            Ok(Some(Self::new(v)))
         }
     }
 
     fn load_from_file(s: String) -> Result<Vec<ExecutableCommand>, Error> {
-        let mut f = File::open(PathBuf::from(&s))?;
-        let mut b: String = String::new();
-        f.read_to_string(&mut b)?;
+        let b = integrity::read(Path::new(&s))?;
         let mut ecs: Vec<ExecutableCommand> = serde_json::from_str(&b)?;
+        for c in &ecs {
+            c.validate()?;
+        }
         ecs.sort_by(|a, b| { a.cmp(b) });
-        return Ok(ecs);
+        Ok(ecs)
     }
 
     pub fn as_commands(&self) -> Vec<ExecutableCommand> {
@@ -116,7 +246,7 @@ impl CommandSet {
 
 #[cfg(test)]
 mod test {
-    use super::CommandSet;
+    use super::{CommandSet, Vars};
     #[test]
     fn test_from_file() {
         let cs = CommandSet::from_file("./testresource/testcommands.json").unwrap();
@@ -137,25 +267,67 @@ mod test {
         assert!(cs.is_err());
     }
 
+    #[test]
+    fn test_load_expectations() {
+        let cs = CommandSet::from_file("./testresource/expectcommands.json").unwrap();
+        let cm = cs.as_commands();
+        assert!(cm[0].expect.is_none());
+        let e = cm[1].expect.as_ref().unwrap();
+        assert_eq!(e.exit_code, vec![0]);
+        assert_eq!(e.stdout_contains, vec!["haha".to_owned()]);
+        assert!(e.stderr_empty);
+    }
+
+    #[test]
+    fn test_expand_with_items() {
+        let cs = CommandSet::from_file("./testresource/loopcommands.json").unwrap();
+        let cm = cs.as_commands();
+        let mut vars = Vars::new();
+        vars.insert("env".to_owned(), "prod".to_owned());
+        let ecs = cm[0].expand(&vars).unwrap();
+        assert_eq!(ecs.len(), 2);
+        assert_eq!(ecs[0].args, vec!["restart".to_owned(), "web@prod".to_owned()]);
+        assert_eq!(ecs[1].args, vec!["restart".to_owned(), "db@prod".to_owned()]);
+        assert!(ecs[0].with_items.is_empty());
+        assert!(cm[0].expand(&Vars::new()).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let base = CommandSet::from_file("./testresource/layout/commands/base.json").unwrap();
+        let extra = CommandSet::from_file("./testresource/layout/commands/extra.json").unwrap();
+        let conflict = CommandSet::from_file("./testresource/layout/commands/conflict.json").unwrap();
+        let cs = CommandSet::merge(vec![
+            ("base.json".to_owned(), base.clone()),
+            ("extra.json".to_owned(), extra),
+            ("base.json".to_owned(), base.clone()),
+        ]).unwrap();
+        let idx: Vec<usize> = cs.commands.iter().map(|c| c.idx).collect();
+        assert_eq!(idx, vec![1, 2, 10]);
+        assert_eq!(cs.select_command_by_index(&[10]).unwrap().unwrap().commands[0].command, "uptime");
+        let e = CommandSet::merge(vec![
+            ("base.json".to_owned(), base),
+            ("conflict.json".to_owned(), conflict),
+        ]);
+        assert!(e.is_err());
+    }
+
     #[test]
     fn test_select_command_by_index() {
         let cs = CommandSet::from_file("./testresource/multiplecommands.json").unwrap();
-        let cs2 = cs.select_command_by_index(&[3,5,6]).unwrap();
+        let cs2 = cs.select_command_by_index(&[3,5,6]).unwrap().unwrap();
         let cm = cs2.commands;
         assert_eq!(cm[0].idx, 3);
         assert_eq!(cm[1].idx, 5);
         assert_eq!(cm[2].idx, 6);
+        assert!(cs.select_command_by_index(&[]).unwrap().is_none());
     }
 
     #[test]
-    #[should_panic]
-    fn test_select_command_by_index_panic() {
+    fn test_select_command_by_index_missing() {
         let cs = CommandSet::from_file("./testresource/multiplecommands.json").unwrap();
-        // No command with idx over 6, so 7 triggered panic.
-        let cs2 = cs.select_command_by_index(&[3,5,7]).unwrap();
-        let cm = cs2.commands;
-        assert_eq!(cm[0].idx, 3);
-        assert_eq!(cm[1].idx, 5);
-        assert_eq!(cm[2].idx, 7);
+        // No command with idx over 6.
+        let e = cs.select_command_by_index(&[3,5,7]).unwrap_err();
+        assert_eq!(e.to_string(), "No command with index `7` in the command pool!");
     }
 }
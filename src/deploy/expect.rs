@@ -0,0 +1,199 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use super::{Serialize, Deserialize};
+use crate::Error;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Assertions checked against the result of an `ExecutableCommand`.
+///
+/// Every field is optional, an empty `exit_code` set means the command must
+/// exit with `0`.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct Expectation {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exit_code: Vec<i32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stdout_contains: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stdout_matches: Vec<String>,
+    #[serde(default)]
+    pub stderr_empty: bool,
+    /// Dotted paths into stdout parsed as JSON, e.g. `data.items[0].name`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub json: BTreeMap<String, Value>,
+}
+
+impl Expectation {
+    /// Makes sure every regex and json path can be used before running anything.
+    pub fn validate(&self) -> Result<(), Error> {
+        for r in &self.stdout_matches {
+            Regex::new(r).map_err(|e| Error::Assertion(format!("Invalid regex `{}`: {}", r, e)))?;
+        }
+        for p in self.json.keys() {
+            json_pointer(p)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the result of a finished command, collecting every failed
+    /// assertion into one diff-style message.
+    pub fn check(&self, exit_code: Option<i32>, stdout: &str, stderr: &str) -> Result<(), Error> {
+        let mut failures: Vec<String> = Vec::new();
+        let codes: &[i32] = if self.exit_code.is_empty() { &[0] } else { &self.exit_code };
+        if !exit_code.is_some_and(|c| codes.contains(&c)) {
+            let actual = match exit_code {
+                Some(c) => c.to_string(),
+                None => "killed by signal".to_owned(),
+            };
+            failures.push(diff("exit code", &format!("one of {:?}", codes), &actual));
+        }
+        for s in &self.stdout_contains {
+            if !stdout.contains(s.as_str()) {
+                failures.push(diff("stdout contains", &format!("{:?}", s), &tail(stdout)));
+            }
+        }
+        for r in &self.stdout_matches {
+            let re = Regex::new(r).map_err(|e| Error::Assertion(format!("Invalid regex `{}`: {}", r, e)))?;
+            if !re.is_match(stdout) {
+                failures.push(diff("stdout matches", &format!("/{}/", r), &tail(stdout)));
+            }
+        }
+        if self.stderr_empty && !stderr.trim().is_empty() {
+            failures.push(diff("stderr empty", "\"\"", &tail(stderr)));
+        }
+        if !self.json.is_empty() {
+            match serde_json::from_str::<Value>(stdout) {
+                Ok(doc) => {
+                    for (path, expected) in &self.json {
+                        let actual = doc.pointer(&json_pointer(path)?);
+                        if actual != Some(expected) {
+                            let actual = actual.map_or("<missing>".to_owned(), |v| v.to_string());
+                            failures.push(diff(&format!("json `{}`", path), &expected.to_string(), &actual));
+                        }
+                    }
+                },
+                Err(e) => {
+                    failures.push(diff("stdout is json", "valid json", &format!("{}", e)));
+                },
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Assertion(failures.join("\n")))
+        }
+    }
+}
+
+fn diff(what: &str, expected: &str, actual: &str) -> String {
+    let mut s = format!("  {}\n  - expected: {}", what, expected);
+    let mut lines = actual.lines();
+    s.push_str(&format!("\n  + actual:   {}", lines.next().unwrap_or("")));
+    for l in lines {
+        s.push_str(&format!("\n  +           {}", l));
+    }
+    s
+}
+
+/// Last few lines of an output stream, enough to see what went wrong.
+fn tail(s: &str) -> String {
+    static LINES: usize = 10;
+    let v: Vec<&str> = s.trim_end().lines().collect();
+    let start = v.len().saturating_sub(LINES);
+    v[start..].join("\n")
+}
+
+/// Translates `a.b[0].c` (optionally prefixed with `$.`) into a JSON pointer.
+fn json_pointer(path: &str) -> Result<String, Error> {
+    let p = path.trim_start_matches('$').trim_start_matches('.');
+    let mut pointer = String::new();
+    for seg in p.split('.').filter(|s| !s.is_empty()) {
+        let (key, rest) = match seg.find('[') {
+            Some(i) => seg.split_at(i),
+            None => (seg, ""),
+        };
+        if !key.is_empty() {
+            pointer.push('/');
+            pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+        }
+        for idx in rest.split(']').filter(|s| !s.is_empty()) {
+            let idx = idx.trim_start_matches('[');
+            if idx.parse::<usize>().is_err() {
+                return Err(Error::Assertion(format!("Invalid json path `{}`", path)));
+            }
+            pointer.push('/');
+            pointer.push_str(idx);
+        }
+    }
+    Ok(pointer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Expectation, json_pointer};
+
+    #[test]
+    fn test_json_pointer() {
+        assert_eq!(json_pointer("$.data.items[0].name").unwrap(), "/data/items/0/name");
+        assert_eq!(json_pointer("a.b").unwrap(), "/a/b");
+        assert!(json_pointer("a[x]").is_err());
+    }
+
+    #[test]
+    fn test_default_requires_success() {
+        let e = Expectation::default();
+        assert!(e.check(Some(0), "", "").is_ok());
+        assert!(e.check(Some(1), "", "").is_err());
+        assert!(e.check(None, "", "").is_err());
+    }
+
+    #[test]
+    fn test_check_output() {
+        let e: Expectation = serde_json::from_str(r#"{
+            "exit_code": [0, 2],
+            "stdout_contains": ["ok"],
+            "stdout_matches": ["^\\{"],
+            "stderr_empty": true,
+            "json": {"status": "ok", "$.items[1]": 2}
+        }"#).unwrap();
+        assert!(e.validate().is_ok());
+        assert!(e.check(Some(2), r#"{"status": "ok", "items": [1, 2]}"#, "").is_ok());
+        let err = e.check(Some(0), r#"{"status": "bad", "items": [1]}"#, "warning").unwrap_err();
+        let msg = format!("{}", err);
+        assert!(msg.contains("json `status`"));
+        assert!(msg.contains("json `$.items[1]`"));
+        assert!(msg.contains("stderr empty"));
+        assert!(!msg.contains("exit code"));
+    }
+
+    #[test]
+    fn test_validate_bad_regex() {
+        let e = Expectation {
+            stdout_matches: vec!["(".to_owned()],
+            ..Expectation::default()
+        };
+        assert!(e.validate().is_err());
+    }
+}
@@ -21,90 +21,456 @@
 //! SOFTWARE.
 //! 
 use super::{
-    File,
-    Read,
     Serialize,
     Deserialize,
+    Path,
     PathBuf,
-    task::DeployTask
+    DeployTask,
+    template::{self, Vars}
 };
 use crate::Error;
+use crate::identity::Identity;
+use crate::integrity;
 use crate::config::Config;
-use serde_json;
+use crate::event::Event;
+use crate::report::{Status, TaskReport};
+use crate::runner::RunContext;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Instant;
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct DeployPlan {
     pub name: String,
+    #[serde(default)]
     pub tasknames: Vec<String>,
-    #[serde(skip_serializing, skip_deserializing)]
-    tasks: Option<Vec<DeployTask>>,
+    /// Ordered tasks and nested plans, runs after `tasknames`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<PlanStep>,
+    /// Default variables for templates, callers may override them.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub vars: Vars,
+    /// Default `user` of the commands of the plan's tasks and nested plans.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Default `group` of the commands of the plan's tasks and nested plans.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Name of the run lock the plan takes, shared by every plan giving
+    /// the same name. The plan's own name if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock: Option<String>,
     #[serde(skip_serializing, skip_deserializing)]
     config: Option<Config>,
 }
 
+/// One step of a plan, either a task from `Config::taskfiles` or another
+/// plan from `Config::plan_files`.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum PlanStep {
+    Task {
+        task: String,
+        /// Values for the parameters declared by the task.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        args: BTreeMap<String, Value>,
+    },
+    Plan {
+        plan: String,
+        /// Passed down to the nested plan along with whatever the caller was
+        /// passed itself, may use the caller's variables.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        vars: Vars,
+    },
+}
+
 impl DeployPlan {
 
-    pub fn from_file(s: &str, config: Option<Config>) -> DeployPlan {
+    pub fn new(name: &str, steps: Vec<PlanStep>, vars: Vars) -> Self {
+        DeployPlan {
+            name: name.to_owned(),
+            tasknames: Vec::new(),
+            steps,
+            vars,
+            user: None,
+            group: None,
+            lock: None,
+            config: None,
+        }
+    }
+
+    pub fn from_file(s: &str, config: Option<Config>) -> Result<DeployPlan, Error> {
         let mut dp = DeployPlan::load_from_file(s.to_owned())
-                            .expect(&format!("Error loading DeployPlan `{}`!", s));
+                            .map_err(|e| Error::Failed(format!("Error loading DeployPlan `{}`: {}!", s, e)))?;
         dp.config = config;
-        dp
+        Ok(dp)
+    }
+
+    /// Every step of the plan in execution order, `tasknames` first.
+    pub fn all_steps(&self) -> Vec<PlanStep> {
+        let mut v: Vec<PlanStep> = self.tasknames.iter()
+            .map(|t| PlanStep::Task { task: t.clone(), args: BTreeMap::new() })
+            .collect();
+        v.extend(self.steps.iter().cloned());
+        v
+    }
+
+    /// Runs the plan with `vars` layered over its own defaults, nested plans
+    /// are looked up in `plans`.
+    pub fn run(&mut self, vars: &Vars, plans: &mut HashMap<String, DeployPlan>, ctx: &mut RunContext) -> Result<(), Error> {
+        ctx.observer.on_event(&Event::PlanStarted { plan: self.name.clone() });
+        let started = Instant::now();
+        let result = self.run_steps(vars, plans, ctx);
+        ctx.observer.on_event(&Event::PlanFinished {
+            plan: self.name.clone(),
+            status: if result.is_ok() { Status::Success } else { Status::Failed },
+            duration: started.elapsed(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        result
     }
 
-    pub fn run(&mut self) -> Result<(), Error> {
-        if self.tasks.is_none() {
-            self.load_task()?
+    fn run_steps(&mut self, outer: &Vars, plans: &mut HashMap<String, DeployPlan>, ctx: &mut RunContext) -> Result<(), Error> {
+        // Config (and profile) variables, then the plan's own defaults, then
+        // the profile's defaults for this plan, then whatever the caller set.
+        let mut vars_in_use = self.config.as_ref().map(|c| c.vars_in_use()).unwrap_or_default();
+        vars_in_use.extend(self.vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if let Some(c) = &self.config {
+            vars_in_use.extend(c.plan_defaults(&self.name));
         }
-        for t in self.tasks.take().as_mut().unwrap() {
-            t.run()?
+        vars_in_use.extend(outer.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let steps = self.all_steps();
+        for (i, step) in steps.iter().enumerate() {
+            if let Err(e) = self.run_step(step, &vars_in_use, outer, plans, ctx) {
+                skip_steps(&self.name, &steps[i + 1..], plans, ctx);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn run_step(&mut self, step: &PlanStep, vars_in_use: &Vars, outer: &Vars, plans: &mut HashMap<String, DeployPlan>, ctx: &mut RunContext) -> Result<(), Error> {
+        match step {
+            PlanStep::Task { task, args } => {
+                let mut t = self.load_task(task)?;
+                if t.user.is_none() {
+                    t.user = self.user.clone();
+                }
+                if t.group.is_none() {
+                    t.group = self.group.clone();
+                }
+                let vars = t.bind_args(args, vars_in_use)?;
+                t.run(&self.name, &vars, ctx)
+            },
+            PlanStep::Plan { plan, vars } => {
+                // A plan being run is taken out of the map, so a missing
+                // one here means we came back to it through a cycle.
+                let mut p = match plans.remove(plan) {
+                    Some(p) => p,
+                    None => return Err(Error::Failed(format!("Plan `{}` is not available, it does not exist or is already running!", plan))),
+                };
+                // The nested plan keeps its own defaults, only what this
+                // plan was passed and the step's variables go down.
+                let mut passed = outer.clone();
+                for (k, v) in vars {
+                    passed.insert(k.clone(), template::render(v, vars_in_use)?);
+                }
+                // Inherits this plan's user and group for this run only.
+                let own = (p.user.clone(), p.group.clone());
+                p.user = own.0.clone().or_else(|| self.user.clone());
+                p.group = own.1.clone().or_else(|| self.group.clone());
+                let r = p.run(&passed, plans, ctx);
+                p.user = own.0;
+                p.group = own.1;
+                plans.insert(plan.clone(), p);
+                r
+            },
+        }
+    }
+
+    /// Checks the arguments of every task step against the task's parameters.
+    pub fn validate(&self) -> Result<(), Error> {
+        Identity::resolve(self.user.as_deref(), self.group.as_deref())
+            .map_err(|e| Error::Failed(format!("Plan `{}`: {}", &self.name, e)))?;
+        for step in self.all_steps() {
+            if let PlanStep::Task { task, args } = step {
+                self.load_task(&task)?.check_args(&args)
+                    .map_err(|e| Error::Failed(format!("Plan `{}`: {}", &self.name, e)))?;
+            }
         }
         Ok(())
     }
 
-    fn load_task(&mut self) -> Result<(), Error> {
-        let config = self.config.clone().unwrap();
-        let task_files = &config.taskfiles;
-        let config_dir = &config.config_dir;
-        let mut v: Vec<DeployTask> = Vec::with_capacity(self.tasknames.len());
-        for t in &self.tasknames {
-            match task_files.get(t) {
-                Some(f) => {
-                    let path = PathBuf::from(config_dir).join(f);
-                    let task = DeployTask::from_file(path.to_str().unwrap(), Some(config.clone()))?;
-                    v.push(task);
+    /// Checks every placeholder of the plan's steps, tasks and commands
+    /// against the variables the plan has, `inherited` being the ones its
+    /// callers may pass down.
+    pub fn check_vars(&self, inherited: &BTreeSet<String>) -> Result<(), Error> {
+        let known = self.known_vars(inherited);
+        for step in self.all_steps() {
+            match step {
+                PlanStep::Task { task, args } => {
+                    let t = self.load_task(&task)?;
+                    args.values().filter_map(|v| v.as_str()).try_for_each(|s| template::check(s, &known))
+                        .and_then(|_| t.check_vars(&known))
+                        .map_err(|e| Error::Failed(format!("Plan `{}`, task `{}`: {}", &self.name, task, e)))?;
+                },
+                PlanStep::Plan { plan, vars } => {
+                    vars.values().try_for_each(|s| template::check(s, &known))
+                        .map_err(|e| Error::Failed(format!("Plan `{}`, step `{}`: {}", &self.name, plan, e)))?;
                 },
-                None => {},
             }
         }
-        self.tasks = Some(v);
         Ok(())
     }
 
+    /// Names of the variables of the plan: the config's, its own defaults
+    /// and `inherited`.
+    fn known_vars(&self, inherited: &BTreeSet<String>) -> BTreeSet<String> {
+        let mut known = inherited.clone();
+        if let Some(c) = &self.config {
+            known.extend(c.vars_in_use().into_keys());
+            known.extend(c.plan_defaults(&self.name).into_keys());
+        }
+        known.extend(self.vars.keys().cloned());
+        known
+    }
+
+    /// Attaches the config used to find tasks and commands when running.
+    pub fn set_config(&mut self, config: Option<Config>) {
+        self.config = config;
+    }
+
+    fn load_task(&self, name: &str) -> Result<DeployTask, Error> {
+        let config = match self.config.clone() {
+            Some(c) => c,
+            None => return Err(Error::Failed(format!("Plan `{}` has no config to find task `{}` in!", &self.name, name))),
+        };
+        if let Some(t) = config.task(name) {
+            let mut task = t.clone();
+            task.set_config(Some(config.clone()));
+            return Ok(task);
+        }
+        match config.taskfiles.get(name) {
+            Some(f) => {
+                let path = PathBuf::from(&config.config_dir).join(f);
+                DeployTask::from_file(path.to_str().unwrap(), Some(config.clone()))
+            },
+            None => Err(Error::Failed(format!("No task named `{}`, referenced by plan `{}`!", name, &self.name))),
+        }
+    }
+
     fn load_from_file(s: String) -> Result<DeployPlan, Error> {
-        let mut f = File::open(PathBuf::from(&s))?;
-        let mut b: String = String::new();
-        f.read_to_string(&mut b)?;
+        let b = integrity::read(Path::new(&s))?;
         let task: DeployPlan = serde_json::from_str(&b)?;
-        return Ok(task);
+        Ok(task)
+    }
+}
+
+/// Reports `steps` of plan `plan` as skipped after an earlier step failed,
+/// the tasks of nested plans included.
+fn skip_steps(plan: &str, steps: &[PlanStep], plans: &HashMap<String, DeployPlan>, ctx: &mut RunContext) {
+    for step in steps {
+        match step {
+            PlanStep::Task { task, .. } => {
+                ctx.observer.on_event(&Event::TaskSkipped {
+                    plan: plan.to_owned(),
+                    task: task.clone(),
+                    item: None,
+                });
+                let mut tr = TaskReport::new(plan, task, None);
+                tr.status = Status::Skipped;
+                ctx.report.tasks.push(tr);
+            },
+            PlanStep::Plan { plan, .. } => {
+                if let Some(p) = plans.get(plan) {
+                    skip_steps(&p.name, &p.all_steps(), plans, ctx);
+                }
+            },
+        }
     }
 }
 
+/// Makes sure every nested plan exists and no plan ends up including itself.
+pub fn check_plan_graph(plans: &HashMap<String, DeployPlan>) -> Result<(), Error> {
+    fn visit(name: &str, plans: &HashMap<String, DeployPlan>, stack: &mut Vec<String>, done: &mut Vec<String>) -> Result<(), Error> {
+        if done.iter().any(|d| d == name) {
+            return Ok(());
+        }
+        if let Some(i) = stack.iter().position(|s| s == name) {
+            let mut cycle = stack[i..].to_vec();
+            cycle.push(name.to_owned());
+            return Err(Error::Failed(format!("Plan cycle detected: {}", cycle.join(" -> "))));
+        }
+        let plan = match plans.get(name) {
+            Some(p) => p,
+            None => return Err(Error::Failed(format!("No plan named `{}`, referenced by `{}`!", name, stack.last().map_or("", |s| s.as_str())))),
+        };
+        stack.push(name.to_owned());
+        for step in &plan.steps {
+            if let PlanStep::Plan { plan, .. } = step {
+                visit(plan, plans, stack, done)?;
+            }
+        }
+        stack.pop();
+        done.push(name.to_owned());
+        Ok(())
+    }
+
+    let mut names: Vec<&String> = plans.keys().collect();
+    names.sort();
+    let mut done: Vec<String> = Vec::new();
+    for n in names {
+        visit(n, plans, &mut Vec::new(), &mut done)?;
+    }
+    Ok(())
+}
+
+/// Checks the placeholders of every plan with `DeployPlan::check_vars`, a
+/// nested plan knowing whatever its callers pass down. Expects a graph
+/// checked by `check_plan_graph`.
+pub fn check_plan_vars(plans: &HashMap<String, DeployPlan>) -> Result<(), Error> {
+    let none = BTreeSet::new();
+    let mut inherited: HashMap<&str, BTreeSet<String>> = HashMap::new();
+    // Callers pass on what they inherit, so repeat until nothing is added.
+    loop {
+        let mut changed = false;
+        for p in plans.values() {
+            let own = inherited.get(p.name.as_str()).unwrap_or(&none).clone();
+            for step in &p.steps {
+                if let PlanStep::Plan { plan, vars } = step {
+                    let passed = inherited.entry(plan.as_str()).or_default();
+                    let before = passed.len();
+                    passed.extend(own.iter().cloned());
+                    passed.extend(vars.keys().cloned());
+                    changed |= passed.len() != before;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    let mut names: Vec<&String> = plans.keys().collect();
+    names.sort();
+    for n in names {
+        plans[n].check_vars(inherited.get(n.as_str()).unwrap_or(&none))?;
+    }
+    Ok(())
+}
+
+/// The run locks of plan `name` and of the plans nested in it, sorted so
+/// runs always take them in the same order. Expects a graph checked by
+/// `check_plan_graph`.
+pub fn plan_locks(name: &str, plans: &HashMap<String, DeployPlan>) -> Vec<String> {
+    fn visit(name: &str, plans: &HashMap<String, DeployPlan>, locks: &mut BTreeSet<String>) {
+        if let Some(p) = plans.get(name) {
+            locks.insert(p.lock.clone().unwrap_or_else(|| p.name.clone()));
+            for step in &p.steps {
+                if let PlanStep::Plan { plan, .. } = step {
+                    visit(plan, plans, locks);
+                }
+            }
+        }
+    }
+    let mut locks = BTreeSet::new();
+    visit(name, plans, &mut locks);
+    locks.into_iter().collect()
+}
+
 #[cfg(test)]
 mod test {
-    use super::DeployPlan;
+    use super::{DeployPlan, PlanStep, check_plan_graph, check_plan_vars};
     use crate::config::Config;
+    use std::collections::{BTreeMap, HashMap};
     #[test]
     fn test_from_file() {
         let config = Some(Config::new(Some("./testresource/config.json".to_owned())).unwrap());
-        let dp = DeployPlan::from_file("./testresource/testplan.json", config);
+        let dp = DeployPlan::from_file("./testresource/testplan.json", config).unwrap();
         assert_eq!(dp.name, "Plan1".to_owned());
         assert_eq!(dp.tasknames.len(), 1);
         assert_eq!(dp.tasknames[0], "Task1".to_owned());
     }
 
+    #[test]
+    fn test_load_steps() {
+        let dp = DeployPlan::from_file("./testresource/nestedplan.json", None).unwrap();
+        let steps = dp.all_steps();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0], PlanStep::Task { task: "Task1".to_owned(), args: BTreeMap::new() });
+        match &steps[1] {
+            PlanStep::Plan { plan, vars } => {
+                assert_eq!(plan, "Plan1");
+                assert_eq!(vars["version"], "{{ version }}");
+            },
+            _ => panic!("Expect a nested plan step!"),
+        }
+        assert_eq!(dp.vars["version"], "latest");
+    }
+
+    #[test]
+    fn test_validate_args() {
+        let config = Some(Config::new(Some("./testresource/config.json".to_owned())).unwrap());
+        let dp = DeployPlan::from_file("./testresource/paramplan.json", config.clone()).unwrap();
+        assert!(dp.validate().is_ok());
+
+        let mut dp: DeployPlan = serde_json::from_str(r#"{"name": "p", "tasknames": ["ParamTask"]}"#).unwrap();
+        dp.config = config;
+        let msg = format!("{}", dp.validate().unwrap_err());
+        assert!(msg.contains("Missing required parameter `service`"));
+    }
+
+    #[test]
+    fn test_check_plan_graph() {
+        let mut plans: HashMap<String, DeployPlan> = HashMap::new();
+        for s in &[
+            r#"{"name": "a", "steps": [{"plan": "b"}]}"#,
+            r#"{"name": "b", "tasknames": ["t"], "steps": [{"plan": "c"}]}"#,
+            r#"{"name": "c", "tasknames": ["t"]}"#,
+        ] {
+            let p: DeployPlan = serde_json::from_str(s).unwrap();
+            plans.insert(p.name.clone(), p);
+        }
+        assert!(check_plan_graph(&plans).is_ok());
+
+        let p: DeployPlan = serde_json::from_str(r#"{"name": "c", "steps": [{"plan": "a"}]}"#).unwrap();
+        plans.insert(p.name.clone(), p);
+        let msg = format!("{}", check_plan_graph(&plans).unwrap_err());
+        assert_eq!(msg, "Plan cycle detected: a -> b -> c -> a");
+
+        let p: DeployPlan = serde_json::from_str(r#"{"name": "c", "steps": [{"plan": "missing"}]}"#).unwrap();
+        plans.insert(p.name.clone(), p);
+        assert!(check_plan_graph(&plans).is_err());
+    }
+
+    #[test]
+    fn test_check_plan_vars() {
+        let config = Some(Config::new(Some("./testresource/config.json".to_owned())).unwrap());
+        let mut plans: HashMap<String, DeployPlan> = HashMap::new();
+        for s in &[
+            r#"{"name": "a", "vars": {"version": "1"}, "steps": [{"plan": "b", "vars": {"tag": "v{{ version }}"}}]}"#,
+            r#"{"name": "b", "steps": [{"plan": "c", "vars": {"image": "app:{{ tag }}"}}]}"#,
+            r#"{"name": "c", "tasknames": ["Task1"]}"#,
+        ] {
+            let mut p: DeployPlan = serde_json::from_str(s).unwrap();
+            p.config = config.clone();
+            plans.insert(p.name.clone(), p);
+        }
+        check_plan_vars(&plans).unwrap();
+
+        let ok = plans.get_mut("b").unwrap().steps[0].clone();
+        plans.get_mut("b").unwrap().steps[0] = serde_json::from_str(r#"{"plan": "c", "vars": {"image": "app:{{ tga }}"}}"#).unwrap();
+        let e = check_plan_vars(&plans).unwrap_err();
+        assert_eq!(e.to_string(), "Plan `b`, step `c`: Unknown variable `tga` in `app:{{ tga }}`");
+        plans.get_mut("b").unwrap().steps[0] = ok;
+
+        plans.get_mut("c").unwrap().tasknames.push("NoSuchTask".to_owned());
+        let e = check_plan_vars(&plans).unwrap_err();
+        assert_eq!(e.to_string(), "No task named `NoSuchTask`, referenced by plan `c`!");
+    }
+
     #[test]
     fn test_load_from_mal_file() {
         let dp = DeployPlan::load_from_file("./testresource/faultjson.json".to_owned());
         assert!(dp.is_err());
     }
-}
\ No newline at end of file
+}
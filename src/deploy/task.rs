@@ -20,24 +20,102 @@
 //! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 //! SOFTWARE.
 //! 
-use serde_json;
-use super::{Serialize, Deserialize, File, Read, PathBuf};
-use super::command::{ExecutableCommand, CommandSet};
+use super::{Serialize, Deserialize, Path};
+use super::{ExecutableCommand, CommandSet};
+use super::expect::Expectation;
+use super::template::{self, Vars};
 use crate::Error;
+use crate::identity::Identity;
+use crate::integrity;
 use crate::config::Config;
+use serde_json::Value;
+use crate::report::{CommandReport, Status, TaskReport};
+use crate::event::{Event, Observer};
+use crate::runner::{CommandRunner, RunContext, Stream};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::thread;
+use std::time::{Instant, SystemTime};
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct DeployTask {
     pub name: String,
     pub commandseq: Vec<usize>,
+    /// Typed inputs supplied by plans as `args`, exposed to templates by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, TaskParam>,
+    /// Runs the whole task once per item, exposed to templates as `{{ item }}`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub with_items: Vec<String>,
+    /// Runs the whole task once per combination of values, every key is
+    /// exposed to templates under its own name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub matrix: BTreeMap<String, Vec<String>>,
+    /// How many expanded items may run at the same time, one by one if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallel: Option<usize>,
+    /// Default `user` of the task's commands, the plan's if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Default `group` of the task's commands, the plan's if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
     #[serde(skip_serializing, skip_deserializing)]
     commands: Option<CommandSet>,
     #[serde(skip_serializing, skip_deserializing)]
     config: Option<Config>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    #[default]
+    String,
+    Integer,
+    Boolean,
+}
+
+impl ParamType {
+    /// Converts an argument into the string handed to templates, failing
+    /// when it does not fit the declared type.
+    fn coerce(self, v: &Value) -> Option<String> {
+        match (self, v) {
+            (ParamType::String, Value::String(s)) => Some(s.clone()),
+            (ParamType::Integer, Value::Number(n)) if n.is_i64() || n.is_u64() => Some(n.to_string()),
+            (ParamType::Integer, Value::String(s)) => s.parse::<i64>().ok().map(|i| i.to_string()),
+            (ParamType::Boolean, Value::Bool(b)) => Some(b.to_string()),
+            (ParamType::Boolean, Value::String(s)) => s.parse::<bool>().ok().map(|b| b.to_string()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct TaskParam {
+    #[serde(rename = "type", default)]
+    pub kind: ParamType,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+}
+
 impl DeployTask {
 
+    pub fn new(name: &str, commandseq: Vec<usize>) -> Self {
+        DeployTask {
+            name: name.to_owned(),
+            commandseq,
+            params: BTreeMap::new(),
+            with_items: Vec::new(),
+            matrix: BTreeMap::new(),
+            parallel: None,
+            user: None,
+            group: None,
+            commands: None,
+            config: None,
+        }
+    }
+
     pub fn from_file(s: &str, config: Option<Config>) -> Result<Self, Error> {
         let mut dt = DeployTask::load_from_file(s.to_owned())?;
         dt.config = config;
@@ -45,15 +123,110 @@ impl DeployTask {
     }
 
     fn load_from_file(s: String) -> Result<DeployTask, Error> {
-        let mut f = File::open(PathBuf::from(&s))?;
-        let mut b: String = String::new();
-        f.read_to_string(&mut b)?;
+        let b = integrity::read(Path::new(&s))?;
         let task: DeployTask = serde_json::from_str(&b)?;
-        return Ok(task);
+        task.validate()?;
+        Ok(task)
+    }
+
+    /// Makes sure every parameter default fits its declared type.
+    pub fn validate(&self) -> Result<(), Error> {
+        for (name, p) in &self.params {
+            if let Some(d) = &p.default {
+                if p.kind.coerce(d).is_none() {
+                    return Err(Error::Failed(format!("Default of parameter `{}` in task `{}` is not a {:?}!", name, &self.name, p.kind)));
+                }
+            }
+        }
+        Identity::resolve(self.user.as_deref(), self.group.as_deref())
+            .map_err(|e| Error::Failed(format!("Task `{}`: {}", &self.name, e)))?;
+        Ok(())
+    }
+
+    /// Attaches the config used to find the command pool when running.
+    pub fn set_config(&mut self, config: Option<Config>) {
+        self.config = config;
+    }
+
+    /// Checks arguments given by a plan without running anything: unknown
+    /// names, missing required values and literals of the wrong type.
+    pub fn check_args(&self, args: &BTreeMap<String, Value>) -> Result<(), Error> {
+        for name in args.keys() {
+            if !self.params.contains_key(name) {
+                return Err(Error::Failed(format!("Task `{}` has no parameter `{}`!", &self.name, name)));
+            }
+        }
+        for (name, p) in &self.params {
+            match args.get(name) {
+                // Templates can only be checked once variables are known.
+                Some(Value::String(s)) if s.contains("{{") => {},
+                Some(v) => {
+                    if p.kind.coerce(v).is_none() {
+                        return Err(Error::Failed(format!("Parameter `{}` of task `{}` expects a {:?}, got `{}`!", name, &self.name, p.kind, v)));
+                    }
+                },
+                None => {
+                    if p.required && p.default.is_none() {
+                        return Err(Error::Failed(format!("Missing required parameter `{}` of task `{}`!", name, &self.name)));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves arguments against `vars` and fills in defaults, giving the
+    /// variables the task runs with. An optional parameter without an
+    /// argument or default is empty unless `vars` has it already.
+    pub fn bind_args(&self, args: &BTreeMap<String, Value>, vars: &Vars) -> Result<Vars, Error> {
+        self.check_args(args)?;
+        let mut bound = vars.clone();
+        for (name, p) in &self.params {
+            let v = match args.get(name).or(p.default.as_ref()) {
+                Some(Value::String(s)) => Value::String(template::render(s, vars)?),
+                Some(v) => v.clone(),
+                None => {
+                    bound.entry(name.clone()).or_default();
+                    continue;
+                },
+            };
+            match p.kind.coerce(&v) {
+                Some(s) => { bound.insert(name.clone(), s); },
+                None => return Err(Error::Failed(format!("Parameter `{}` of task `{}` expects a {:?}, got `{}`!", name, &self.name, p.kind, v))),
+            }
+        }
+        Ok(bound)
     }
 
-    pub fn load_commands(&mut self, cs: &CommandSet) {
-        self.commands = cs.select_command_by_index(&self.commandseq);
+    /// Checks the placeholders of the parameter defaults and the commands
+    /// against `known`, the variables of the plan running the task.
+    pub fn check_vars(&self, known: &BTreeSet<String>) -> Result<(), Error> {
+        for p in self.params.values() {
+            if let Some(Value::String(s)) = &p.default {
+                template::check(s, known)?;
+            }
+        }
+        let mut known = known.clone();
+        known.extend(self.params.keys().cloned());
+        known.extend(self.matrix.keys().cloned());
+        if !self.with_items.is_empty() {
+            known.insert("item".to_owned());
+        }
+        let commands = match self.config.clone() {
+            Some(mut c) => c.get_commandpool()?.select_command_by_index(&self.commandseq)
+                .map_err(|e| Error::Failed(format!("Task `{}`: {}", &self.name, e)))?,
+            None => self.commands.clone(),
+        };
+        for c in commands.map(|c| c.as_commands()).unwrap_or_default() {
+            c.check_vars(&known).map_err(|e| Error::Failed(format!("Command {}: {}", c.idx, e)))?;
+        }
+        Ok(())
+    }
+
+    pub fn load_commands(&mut self, cs: &CommandSet) -> Result<(), Error> {
+        self.commands = cs.select_command_by_index(&self.commandseq)
+            .map_err(|e| Error::Failed(format!("Task `{}`: {}", &self.name, e)))?;
+        Ok(())
     }
 
     pub fn as_commands(&mut self) -> Result<Vec<ExecutableCommand>, Error> {
@@ -63,28 +236,254 @@ impl DeployTask {
         Ok(self.commands.take().unwrap().as_commands())
     }
 
-    pub fn run(&mut self) -> Result<(), Error> {
+    /// Loop variables of every concrete execution, the matrix crossed with
+    /// `with_items`. A task without loops expands into a single empty set.
+    pub fn expand_items(&self) -> Vec<BTreeMap<String, String>> {
+        let mut items: Vec<BTreeMap<String, String>> = vec![BTreeMap::new()];
+        let mut axes: Vec<(&str, &Vec<String>)> = self.matrix.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        if !self.with_items.is_empty() {
+            axes.push(("item", &self.with_items));
+        }
+        for (key, values) in axes {
+            let mut next = Vec::with_capacity(items.len() * values.len());
+            for it in &items {
+                for v in values {
+                    let mut it = it.clone();
+                    it.insert(key.to_owned(), v.clone());
+                    next.push(it);
+                }
+            }
+            items = next;
+        }
+        items
+    }
+
+    /// Runs the task as part of `plan` through the context's runner, adding
+    /// one `TaskReport` per expanded item to the context's report.
+    pub fn run(&mut self, plan: &str, vars: &Vars, ctx: &mut RunContext) -> Result<(), Error> {
         if self.commands.is_none() {
-            let config = self.config.as_mut().unwrap();
-            let cs = config.get_commandpool();        
-            self.load_commands(&cs);
+            let cs = match self.config.as_mut() {
+                Some(config) => config.get_commandpool()?,
+                None => return Err(Error::Failed(format!("Task `{}` has no commands and no config to take them from!", &self.name))),
+            };
+            self.load_commands(&cs)?;
         }
-        println!("Running task: {}", &self.name);
-        let commands = self.as_commands()?;
-        for c in commands {
-            println!("Executing: `{} {:?}`", c.command, c.args);
-            let output = c.as_command().output().expect("Error executing commands!");
-            println!("{}", String::from_utf8(output.stdout).unwrap());
+        let mut commands = self.as_commands()?;
+        for c in &mut commands {
+            if c.user.is_none() {
+                c.user = self.user.clone();
+            }
+            if c.group.is_none() {
+                c.group = self.group.clone();
+            }
+        }
+        let mut env = self.config.as_ref().map(|c| c.env_in_use()).unwrap_or_default();
+        env.extend(ctx.secrets.env().iter().map(|(k, v)| (k.clone(), v.clone())));
+        let scope = ItemScope {
+            plan,
+            task: &self.name,
+            commands: &commands,
+            env: &env,
+            runner: ctx.runner,
+            observer: ctx.observer,
+        };
+        if self.with_items.is_empty() && self.matrix.is_empty() {
+            let (tr, r) = scope.run(None, vars);
+            ctx.report.tasks.push(tr);
+            return r;
+        }
+
+        let items = self.expand_items();
+        let width = self.parallel.unwrap_or(1).max(1);
+        let mut results: Vec<(TaskReport, Result<(), Error>)> = Vec::with_capacity(items.len());
+        for chunk in items.chunks(width) {
+            let item_vars: Vec<(String, Vars)> = chunk.iter().map(|it| {
+                let mut v = vars.clone();
+                v.extend(it.iter().map(|(k, x)| (k.clone(), x.clone())));
+                (item_label(it), v)
+            }).collect();
+            if width == 1 {
+                let (label, v) = &item_vars[0];
+                results.push(scope.run(Some(label.clone()), v));
+            } else {
+                let scope = &scope;
+                thread::scope(|s| {
+                    let handles: Vec<_> = item_vars.iter()
+                        .map(|(label, v)| (label, s.spawn(move || scope.run(Some(label.clone()), v))))
+                        .collect();
+                    for (label, h) in handles {
+                        results.push(h.join().unwrap_or_else(|_| {
+                            let mut tr = TaskReport::new(plan, scope.task, Some(label.clone()));
+                            tr.status = Status::Failed;
+                            (tr, Err(Error::from("Task item panicked!")))
+                        }));
+                    }
+                });
+            }
+            if results.iter().any(|(_, r)| r.is_err()) {
+                break;
+            }
+        }
+
+        let mut failed: Vec<String> = Vec::new();
+        let ran = results.len();
+        for (tr, r) in results {
+            if r.is_err() {
+                failed.push(tr.item.clone().unwrap_or_default());
+            }
+            ctx.report.tasks.push(tr);
+        }
+        for it in &items[ran..] {
+            let label = item_label(it);
+            ctx.observer.on_event(&Event::TaskSkipped {
+                plan: plan.to_owned(),
+                task: self.name.clone(),
+                item: Some(label.clone()),
+            });
+            let mut tr = TaskReport::new(plan, &self.name, Some(label));
+            tr.status = Status::Skipped;
+            ctx.report.tasks.push(tr);
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Failed(format!("Task `{}` failed for [{}]", &self.name, failed.join("], ["))))
         }
-        Ok(())
     }
 }
 
+/// What every item of a task shares, items running in parallel borrow it
+/// from their own threads.
+struct ItemScope<'a> {
+    plan: &'a str,
+    task: &'a str,
+    commands: &'a [ExecutableCommand],
+    env: &'a HashMap<String, String>,
+    runner: &'a dyn CommandRunner,
+    observer: &'a dyn Observer,
+}
+
+impl ItemScope<'_> {
+    fn run(&self, item: Option<String>, vars: &Vars) -> (TaskReport, Result<(), Error>) {
+        self.observer.on_event(&Event::TaskStarted {
+            plan: self.plan.to_owned(),
+            task: self.task.to_owned(),
+            item: item.clone(),
+        });
+        let started = Instant::now();
+        let mut tr = TaskReport::new(self.plan, self.task, item);
+        let mut result = Ok(());
+        'commands: for c in self.commands {
+            let expanded = match c.expand(vars) {
+                Ok(v) => v,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                },
+            };
+            for c in expanded {
+                let (cr, r) = self.execute(&c, &tr.item);
+                tr.commands.push(cr);
+                if r.is_err() {
+                    result = r;
+                    break 'commands;
+                }
+            }
+        }
+        tr.duration = started.elapsed();
+        if let Err(e) = &result {
+            tr.status = Status::Failed;
+            tr.error = Some(e.to_string());
+        }
+        self.observer.on_event(&Event::TaskFinished {
+            plan: self.plan.to_owned(),
+            task: self.task.to_owned(),
+            item: tr.item.clone(),
+            status: tr.status,
+            duration: tr.duration,
+            error: tr.error.clone(),
+        });
+        (tr, result)
+    }
+
+    fn execute(&self, c: &ExecutableCommand, item: &Option<String>) -> (CommandReport, Result<(), Error>) {
+        self.observer.on_event(&Event::CommandStarted {
+            plan: self.plan.to_owned(),
+            task: self.task.to_owned(),
+            item: item.clone(),
+            command: c.command.clone(),
+            args: c.args.clone(),
+        });
+        let started = Instant::now();
+        let mut cr = CommandReport {
+            idx: c.idx,
+            command: c.command.clone(),
+            args: c.args.clone(),
+            status: Status::Success,
+            started: SystemTime::now(),
+            exit_code: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            duration: Default::default(),
+            error: None,
+        };
+        let on_line = |stream: Stream, line: &str| {
+            self.observer.on_event(&Event::OutputLine {
+                plan: self.plan.to_owned(),
+                task: self.task.to_owned(),
+                item: item.clone(),
+                command: c.command.clone(),
+                stream,
+                line: line.to_owned(),
+            });
+        };
+        let result = match self.runner.run(c, self.env, &on_line) {
+            Ok(output) => {
+                cr.exit_code = output.exit_code;
+                cr.stdout = output.stdout;
+                cr.stderr = output.stderr;
+                match &c.expect {
+                    Some(e) => e.check(cr.exit_code, &cr.stdout, &cr.stderr),
+                    None => Expectation::default().check(cr.exit_code, &cr.stdout, &cr.stderr),
+                }
+            },
+            Err(e) => Err(e),
+        };
+        cr.duration = started.elapsed();
+        if let Err(e) = &result {
+            cr.status = Status::Failed;
+            cr.error = Some(e.to_string());
+        }
+        self.observer.on_event(&Event::CommandFinished {
+            plan: self.plan.to_owned(),
+            task: self.task.to_owned(),
+            item: item.clone(),
+            command: cr.command.clone(),
+            args: cr.args.clone(),
+            status: cr.status,
+            exit_code: cr.exit_code,
+            duration: cr.duration,
+            error: cr.error.clone(),
+        });
+        (cr, result)
+    }
+}
+
+fn item_label(item: &BTreeMap<String, String>) -> String {
+    item.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<String>>().join(", ")
+}
+
 #[cfg(test)]
 mod test {
-    use super::DeployTask;
+    use super::{DeployTask, Vars};
+    use serde_json::Value;
+    use std::collections::BTreeMap;
     use crate::deploy::command::CommandSet;
     use crate::config::Config;
+    use crate::event::Observers;
+    use crate::report::RunReport;
+    use crate::runner::{LocalRunner, MockRunner, RunContext};
+    use crate::secret::Secrets;
     #[test]
     fn from_file() {
         let config = Some(Config::new(Some("./testresource/config.json".to_owned())).unwrap());
@@ -100,10 +499,98 @@ mod test {
         let config = Some(Config::new(Some("./testresource/config.json".to_owned())).unwrap());
         let mut dt = DeployTask::from_file("./testresource/testtasks.json", config).unwrap();
         let cs = CommandSet::from_file("./testresource/multiplecommands.json").unwrap();
-        dt.load_commands(&cs);
+        dt.load_commands(&cs).unwrap();
         for (i, c) in dt.commands.take().unwrap().as_commands().iter().enumerate() {
             assert_eq!(c.idx, i + 1);
         }
+        let e = DeployTask::new("Broken", vec![1, 7]).load_commands(&cs).unwrap_err();
+        assert_eq!(e.to_string(), "Task `Broken`: No command with index `7` in the command pool!");
+    }
+
+    #[test]
+    fn test_exit_code_without_expect() {
+        let mut dt: DeployTask = serde_json::from_str(r#"{"name": "Check", "commandseq": [1]}"#).unwrap();
+        let failing = serde_json::from_str(r#"{"idx": 1, "command": "sh", "args": ["-c", "exit 4"]}"#).unwrap();
+        dt.load_commands(&CommandSet::new(vec![failing])).unwrap();
+        let mut report = RunReport::new("Check");
+        let mut ctx = RunContext {
+            runner: &LocalRunner,
+            observer: &Observers::new(),
+            secrets: &Secrets::default(),
+            report: &mut report,
+        };
+        let e = dt.run("Check", &Vars::new(), &mut ctx).unwrap_err();
+        assert!(e.to_string().contains("exit code"), "{}", e);
+    }
+
+    #[test]
+    fn test_run_without_config() {
+        let mut dt = DeployTask::from_file("./testresource/looptasks.json", None).unwrap();
+        let mut report = RunReport::new("Plan");
+        let mut ctx = RunContext {
+            runner: &MockRunner::new(),
+            observer: &Observers::new(),
+            secrets: &Secrets::default(),
+            report: &mut report,
+        };
+        let e = dt.run("Plan", &Vars::new(), &mut ctx).unwrap_err();
+        assert_eq!(e.to_string(), "Task `LoopTask` has no commands and no config to take them from!");
+    }
+
+    #[test]
+    fn test_expand_items() {
+        let dt = DeployTask::from_file("./testresource/looptasks.json", None).unwrap();
+        assert_eq!(dt.parallel, Some(2));
+        let items = dt.expand_items();
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[0]["env"], "staging");
+        assert_eq!(items[0]["item"], "web");
+        assert_eq!(items[1]["item"], "db");
+        assert_eq!(items[3]["env"], "prod");
+        assert_eq!(super::item_label(&items[3]), "env=prod, item=db");
+    }
+
+    #[test]
+    fn test_params() {
+        let dt = DeployTask::from_file("./testresource/paramtask.json", None).unwrap();
+        assert_eq!(dt.params.len(), 3);
+        let mut vars = Vars::new();
+        vars.insert("env".to_owned(), "prod".to_owned());
+
+        let args: BTreeMap<String, Value> = serde_json::from_str(r#"{"service": "web-{{ env }}"}"#).unwrap();
+        let bound = dt.bind_args(&args, &vars).unwrap();
+        assert_eq!(bound["service"], "web-prod");
+        assert_eq!(bound["replicas"], "1");
+        assert_eq!(bound["dry_run"], "");
+        vars.insert("dry_run".to_owned(), "false".to_owned());
+        assert_eq!(dt.bind_args(&args, &vars).unwrap()["dry_run"], "false");
+
+        let args: BTreeMap<String, Value> = serde_json::from_str(r#"{"service": "db", "replicas": 3, "dry_run": "true"}"#).unwrap();
+        let bound = dt.bind_args(&args, &vars).unwrap();
+        assert_eq!(bound["replicas"], "3");
+        assert_eq!(bound["dry_run"], "true");
+    }
+
+    #[test]
+    fn test_check_args() {
+        let dt = DeployTask::from_file("./testresource/paramtask.json", None).unwrap();
+        let missing: BTreeMap<String, Value> = BTreeMap::new();
+        assert!(dt.check_args(&missing).is_err());
+        let extra: BTreeMap<String, Value> = serde_json::from_str(r#"{"service": "db", "color": "red"}"#).unwrap();
+        assert!(dt.check_args(&extra).is_err());
+        let wrong: BTreeMap<String, Value> = serde_json::from_str(r#"{"service": "db", "replicas": "many"}"#).unwrap();
+        assert!(dt.check_args(&wrong).is_err());
+        let deferred: BTreeMap<String, Value> = serde_json::from_str(r#"{"service": "db", "replicas": "{{ n }}"}"#).unwrap();
+        assert!(dt.check_args(&deferred).is_ok());
+        assert!(dt.bind_args(&deferred, &Vars::new()).is_err());
+    }
+
+    #[test]
+    fn test_expand_without_loops() {
+        let dt = DeployTask::from_file("./testresource/testtasks.json", None).unwrap();
+        let items = dt.expand_items();
+        assert_eq!(items.len(), 1);
+        assert!(items[0].is_empty());
     }
 
     #[test]
@@ -0,0 +1,156 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use std::collections::{BTreeSet, HashMap};
+
+/// Variables available to `{{ name }}` placeholders in commands.
+pub type Vars = HashMap<String, String>;
+
+/// Starts text kept as it is, for snippets with braces of their own.
+const RAW: &str = "{% raw %}";
+const END_RAW: &str = "{% endraw %}";
+
+/// Replaces every `{{ name }}` in `s` with the value from `vars`, unknown
+/// names are reported as errors rather than silently left in place. Braces
+/// around anything but a name, like Go's `{{ .Names }}`, and text between
+/// `{% raw %}` and `{% endraw %}` are left as they are.
+pub fn render(s: &str, vars: &Vars) -> Result<String, Error> {
+    let mut out = String::with_capacity(s.len());
+    for piece in pieces(s) {
+        match piece {
+            Piece::Text(t) => out.push_str(t),
+            Piece::Var(name) => match vars.get(name) {
+                Some(v) => out.push_str(v),
+                None => return Err(Error::Failed(format!("Unknown variable `{}` in `{}`", name, s))),
+            },
+        }
+    }
+    Ok(out)
+}
+
+/// Names of the variables `s` uses, in order.
+pub fn placeholders(s: &str) -> Vec<&str> {
+    pieces(s).into_iter().filter_map(|p| match p {
+        Piece::Var(name) => Some(name),
+        Piece::Text(_) => None,
+    }).collect()
+}
+
+/// Fails on the first placeholder in `s` naming none of `known`. Secrets
+/// are only resolved once a run starts, so `secrets.*` names pass.
+pub fn check(s: &str, known: &BTreeSet<String>) -> Result<(), Error> {
+    match placeholders(s).into_iter().find(|n| !known.contains(*n) && !n.starts_with("secrets.")) {
+        Some(n) => Err(Error::Failed(format!("Unknown variable `{}` in `{}`", n, s))),
+        None => Ok(()),
+    }
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    Var(&'a str),
+}
+
+fn pieces(s: &str) -> Vec<Piece<'_>> {
+    let mut v = Vec::new();
+    let mut rest = s;
+    loop {
+        let open = rest.find("{{");
+        match (open, rest.find(RAW)) {
+            (_, Some(r)) if open.is_none_or(|o| r < o) => {
+                v.push(Piece::Text(&rest[..r]));
+                let after = &rest[r + RAW.len()..];
+                match after.find(END_RAW) {
+                    Some(e) => {
+                        v.push(Piece::Text(&after[..e]));
+                        rest = &after[e + END_RAW.len()..];
+                    },
+                    None => {
+                        v.push(Piece::Text(after));
+                        return v;
+                    },
+                }
+            },
+            (Some(o), _) => {
+                let after = &rest[o + 2..];
+                match after.find("}}").map(|e| (e, after[..e].trim())) {
+                    Some((e, name)) if is_name(name) => {
+                        v.push(Piece::Text(&rest[..o]));
+                        v.push(Piece::Var(name));
+                        rest = &after[e + 2..];
+                    },
+                    _ => {
+                        v.push(Piece::Text(&rest[..o + 2]));
+                        rest = after;
+                    },
+                }
+            },
+            (None, _) => {
+                v.push(Piece::Text(rest));
+                return v;
+            },
+        }
+    }
+}
+
+/// Variable names start with a letter or `_`, `.` separates parts like
+/// in `secrets.token`.
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{placeholders, render, Vars};
+
+    #[test]
+    fn test_render() {
+        let mut vars = Vars::new();
+        vars.insert("item".to_owned(), "web".to_owned());
+        vars.insert("env".to_owned(), "prod".to_owned());
+        assert_eq!(render("restart {{item}}@{{ env }}", &vars).unwrap(), "restart web@prod");
+        assert_eq!(render("no placeholder", &vars).unwrap(), "no placeholder");
+        assert_eq!(placeholders("restart {{item}}@{{ env }}"), vec!["item", "env"]);
+    }
+
+    #[test]
+    fn test_render_foreign_braces() {
+        let mut vars = Vars::new();
+        vars.insert("x".to_owned(), "1".to_owned());
+        let go = "docker ps --format '{{.Names}}' {{ .Values.image | quote }}";
+        assert_eq!(render(go, &vars).unwrap(), go);
+        assert!(placeholders(go).is_empty());
+        assert_eq!(render("echo {% raw %}{{ end }}{% endraw %} {{ x }}", &vars).unwrap(), "echo {{ end }} 1");
+        assert_eq!(render("{% raw %}{{ y }}", &vars).unwrap(), "{{ y }}");
+        assert_eq!(render("{{ open", &vars).unwrap(), "{{ open");
+        assert_eq!(render("{{{{ x }}", &vars).unwrap(), "{{1");
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let vars = Vars::new();
+        let e = render("echo \"hi {{ x }}\"", &vars).unwrap_err();
+        assert_eq!(format!("{}", e), "Unknown variable `x` in `echo \"hi {{ x }}\"`");
+    }
+}
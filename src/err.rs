@@ -21,13 +21,43 @@
 //! SOFTWARE.
 //! 
 use std::io;
-use serde_json;
+use std::fmt;
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     Serde(serde_json::Error),
     Program(&'static str),
+    Assertion(String),
+    Failed(String),
+    /// A command refused by the config's policy.
+    Policy(String),
+    /// A file not matching its lockfile or signature.
+    Integrity(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "IO error: {}", e),
+            Error::Serde(e) => write!(f, "JSON error: {}", e),
+            Error::Program(e) => write!(f, "{}", e),
+            Error::Assertion(e) => write!(f, "Expectation failed:\n{}", e),
+            Error::Failed(e) => write!(f, "{}", e),
+            Error::Policy(e) => write!(f, "Policy violation: {}", e),
+            Error::Integrity(e) => write!(f, "Integrity check failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Serde(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for Error {
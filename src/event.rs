@@ -0,0 +1,330 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::report::Status;
+use crate::runner::Stream;
+use serde::{Serialize, Serializer};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version of the JSON form of events written by `JsonlObserver`, raised on
+/// every change which is not just an added field or event.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
+/// Something that happened while running a plan. `item` is the label of
+/// an expanded task item like `env=prod, item=web`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PlanStarted {
+        plan: String,
+    },
+    TaskStarted {
+        plan: String,
+        task: String,
+        item: Option<String>,
+    },
+    CommandStarted {
+        plan: String,
+        task: String,
+        item: Option<String>,
+        command: String,
+        args: Vec<String>,
+    },
+    OutputLine {
+        plan: String,
+        task: String,
+        item: Option<String>,
+        command: String,
+        stream: Stream,
+        line: String,
+    },
+    CommandFinished {
+        plan: String,
+        task: String,
+        item: Option<String>,
+        command: String,
+        args: Vec<String>,
+        status: Status,
+        exit_code: Option<i32>,
+        #[serde(rename = "duration_ms", serialize_with = "millis")]
+        duration: Duration,
+        error: Option<String>,
+    },
+    TaskFinished {
+        plan: String,
+        task: String,
+        item: Option<String>,
+        status: Status,
+        #[serde(rename = "duration_ms", serialize_with = "millis")]
+        duration: Duration,
+        error: Option<String>,
+    },
+    /// An item left out because an earlier one failed.
+    TaskSkipped {
+        plan: String,
+        task: String,
+        item: Option<String>,
+    },
+    PlanFinished {
+        plan: String,
+        status: Status,
+        #[serde(rename = "duration_ms", serialize_with = "millis")]
+        duration: Duration,
+        error: Option<String>,
+    },
+}
+
+impl Event {
+    /// The same event with `f` applied to every text which may carry
+    /// command lines or output: items, commands, arguments, output lines
+    /// and errors.
+    pub fn map_text<F: Fn(&str) -> String>(&self, f: F) -> Event {
+        let mut e = self.clone();
+        match &mut e {
+            Event::PlanStarted { .. } => {},
+            Event::TaskStarted { item, .. } | Event::TaskSkipped { item, .. } => {
+                *item = item.as_deref().map(&f);
+            },
+            Event::CommandStarted { item, command, args, .. } => {
+                *item = item.as_deref().map(&f);
+                *command = f(command);
+                *args = args.iter().map(|a| f(a)).collect();
+            },
+            Event::OutputLine { item, command, line, .. } => {
+                *item = item.as_deref().map(&f);
+                *command = f(command);
+                *line = f(line);
+            },
+            Event::CommandFinished { item, command, args, error, .. } => {
+                *item = item.as_deref().map(&f);
+                *command = f(command);
+                *args = args.iter().map(|a| f(a)).collect();
+                *error = error.as_deref().map(&f);
+            },
+            Event::TaskFinished { item, error, .. } => {
+                *item = item.as_deref().map(&f);
+                *error = error.as_deref().map(&f);
+            },
+            Event::PlanFinished { error, .. } => *error = error.as_deref().map(&f),
+        }
+        e
+    }
+}
+
+fn millis<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(d.as_millis() as u64)
+}
+
+/// Receives every event of a run. Items of parallel tasks report from their
+/// own threads, so observers must be shareable.
+pub trait Observer: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+/// Hands every event to each observer in turn.
+#[derive(Default, Clone)]
+pub struct Observers(Vec<Arc<dyn Observer>>);
+
+impl Observers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, o: Arc<dyn Observer>) {
+        self.0.push(o);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Observer for Observers {
+    fn on_event(&self, event: &Event) {
+        for o in &self.0 {
+            o.on_event(event);
+        }
+    }
+}
+
+/// Prints progress and command output to the terminal, on stdout unless
+/// built with `stderr()`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConsoleObserver {
+    to_stderr: bool,
+}
+
+impl ConsoleObserver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps stdout free for machine readable output.
+    pub fn stderr() -> Self {
+        ConsoleObserver { to_stderr: true }
+    }
+
+    fn print(&self, s: String) {
+        if self.to_stderr {
+            eprintln!("{}", s);
+        } else {
+            println!("{}", s);
+        }
+    }
+}
+
+impl Observer for ConsoleObserver {
+    fn on_event(&self, event: &Event) {
+        match event {
+            Event::PlanStarted { plan } => self.print(format!("Running plan: {}", plan)),
+            Event::TaskStarted { task, item: None, .. } => self.print(format!("Running task: {}", task)),
+            Event::TaskStarted { task, item: Some(item), .. } => self.print(format!("Running task: {} [{}]", task, item)),
+            Event::CommandStarted { command, args, .. } => self.print(format!("Executing: `{} {:?}`", command, args)),
+            Event::OutputLine { stream: Stream::Stdout, line, .. } => self.print(line.clone()),
+            Event::OutputLine { stream: Stream::Stderr, line, .. } => eprintln!("{}", line),
+            Event::CommandFinished { status: Status::Failed, task, command, args, .. } => {
+                eprintln!("Step `{} {:?}` of task `{}` failed!", command, args, task);
+            },
+            Event::TaskFinished { task, item: Some(item), error, .. } => match error {
+                Some(e) => self.print(format!("Task `{}` [{}]: failed: {}", task, item, e)),
+                None => self.print(format!("Task `{}` [{}]: ok", task, item)),
+            },
+            Event::TaskSkipped { task, item: Some(item), .. } => self.print(format!("Task `{}` [{}]: skipped", task, item)),
+            _ => {},
+        }
+    }
+}
+
+/// Writes every event as one JSON object per line:
+///
+/// ```text
+/// {"schema":1,"ts_ms":1700000000000,"event":"command_finished","plan":"release",...}
+/// ```
+///
+/// `schema` is `EVENT_SCHEMA_VERSION`, `ts_ms` the time the event was seen
+/// in milliseconds since the Unix epoch, and `event` one of `plan_started`,
+/// `task_started`, `command_started`, `output_line`, `command_finished`,
+/// `task_finished`, `task_skipped` or `plan_finished`. The remaining keys
+/// are the fields of the `Event`, with durations as `duration_ms` and
+/// `stream` as `stdout` or `stderr`.
+pub struct JsonlObserver<W: Write + Send> {
+    out: Mutex<W>,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    schema: u32,
+    ts_ms: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+impl<W: Write + Send> JsonlObserver<W> {
+    pub fn new(out: W) -> Self {
+        JsonlObserver { out: Mutex::new(out) }
+    }
+
+    /// Gives back the writer, mostly for tests.
+    pub fn into_inner(self) -> W {
+        self.out.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write + Send> Observer for JsonlObserver<W> {
+    fn on_event(&self, event: &Event) {
+        let record = Record {
+            schema: EVENT_SCHEMA_VERSION,
+            ts_ms: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            event,
+        };
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        // A closed pipe must not abort the run, the events are lost either way.
+        if let Ok(line) = serde_json::to_string(&record) {
+            let _ = writeln!(out, "{}", line).and_then(|_| out.flush());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, JsonlObserver, Observer, Observers};
+    use crate::report::Status;
+    use crate::runner::Stream;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Event>>);
+
+    impl Observer for Recorder {
+        fn on_event(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_observers() {
+        let a = Arc::new(Recorder::default());
+        let b = Arc::new(Recorder::default());
+        let mut o = Observers::new();
+        assert!(o.is_empty());
+        o.add(a.clone());
+        o.add(b.clone());
+        o.on_event(&Event::PlanStarted { plan: "p".to_owned() });
+        assert_eq!(a.0.lock().unwrap().len(), 1);
+        assert_eq!(*b.0.lock().unwrap(), vec![Event::PlanStarted { plan: "p".to_owned() }]);
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let o = JsonlObserver::new(Vec::new());
+        o.on_event(&Event::OutputLine {
+            plan: "p".to_owned(),
+            task: "t".to_owned(),
+            item: None,
+            command: "echo".to_owned(),
+            stream: Stream::Stderr,
+            line: "hi".to_owned(),
+        });
+        o.on_event(&Event::PlanFinished {
+            plan: "p".to_owned(),
+            status: Status::Failed,
+            duration: Duration::from_millis(1500),
+            error: Some("boom".to_owned()),
+        });
+        let out = String::from_utf8(o.into_inner()).unwrap();
+        let lines: Vec<Value> = out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["schema"], 1);
+        assert!(lines[0]["ts_ms"].as_u64().unwrap() > 0);
+        assert_eq!(lines[0]["event"], "output_line");
+        assert_eq!(lines[0]["stream"], "stderr");
+        assert_eq!(lines[0]["item"], Value::Null);
+        assert_eq!(lines[1]["event"], "plan_finished");
+        assert_eq!(lines[1]["status"], "failed");
+        assert_eq!(lines[1]["duration_ms"], 1500);
+        assert_eq!(lines[1]["error"], "boom");
+    }
+}
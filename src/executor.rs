@@ -20,60 +20,659 @@
 //! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 //! SOFTWARE.
 //! 
-use crate::config::Config;
+use crate::Error;
+use crate::config::{Config, ConfigValue};
 use crate::deploy::DeployPlan;
+use crate::deploy::builder::PlanBundle;
+use crate::deploy::plan::{check_plan_graph, check_plan_vars, plan_locks};
+use crate::event::{Observer, Observers};
+use crate::report::RunReport;
+use crate::runner::{CommandRunner, LocalRunner, RunContext};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::policy::{Policy, PolicyRunner};
+use crate::runlock::{RunLock, GLOBAL_LOCK};
+use crate::secret::{Redacting, Secrets};
+use crate::deploy::template::Vars;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+/// Loads the plans of a config and runs them.
+///
+/// ```no_run
+/// use queenbee::Executor;
+///
+/// use queenbee::event::ConsoleObserver;
+/// use std::sync::Arc;
+///
+/// let mut executor = Executor::new().with_observer(Arc::new(ConsoleObserver::new()));
+/// executor.load_context(Some("config.json".to_owned()), None).unwrap();
+/// let report = executor.run_deploy_plan("release").unwrap();
+/// for t in &report.tasks {
+///     println!("{}: {:?}", t.task, t.status);
+/// }
+/// ```
 pub struct Executor {
     plans: Option<HashMap<String, DeployPlan>>,
     config: Option<Config>,
+    runner: Arc<dyn CommandRunner>,
+    observers: Observers,
+    vault_key: Option<Vec<u8>>,
+    policy: Option<Arc<Policy>>,
+    lock_timeout: Option<Duration>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Executor {
     pub fn new() -> Self {
-        return Executor {
+        Executor {
             plans: None,
             config: None,
+            runner: Arc::new(LocalRunner),
+            observers: Observers::new(),
+            vault_key: None,
+            policy: None,
+            lock_timeout: None,
         }
     }
 
-    pub fn load_context(&mut self, config_path: Option<String>) {
-        let config: Config = Config::new(config_path).expect("Error reading config file!");
+    /// Runs commands through `runner` instead of local child processes.
+    pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Reports the progress of every run to `observer`, in addition to the
+    /// ones added before. Nothing is printed without a `ConsoleObserver`.
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observers.add(observer);
+        self
+    }
+
+    /// Opens the config's vault with `key`, a passphrase or the contents of
+    /// a key file, when a plan runs.
+    pub fn with_vault_key(mut self, key: Vec<u8>) -> Self {
+        self.vault_key = Some(key);
+        self
+    }
+
+    /// Waits up to `timeout` for the run locks held by other runs, instead
+    /// of the config's `lock_timeout_secs`.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    /// Loads the config and its plans, checks the commands against the
+    /// config's policy and drops the run logs going over the config's
+    /// retention limits.
+    pub fn load_context(&mut self, config_path: Option<String>, profile: Option<String>) -> Result<(), Error> {
+        let mut config: Config = Config::new(config_path)?.with_profile(profile)?;
+        let policy = config.policy()?;
+        if let Some(p) = &policy {
+            p.validate(&config.get_commandpool()?, &config.env_in_use())?;
+        }
+        if let Some(logs) = config.log_dir() {
+            logs.prune()?;
+        }
+        self.policy = policy.map(Arc::new);
         self.config = Some(config);
-        self.prepair_deploy_plan();
+        self.prepair_deploy_plan()
     }
-    
-    fn prepair_deploy_plan(&mut self) {
-        let mut m: HashMap<String, DeployPlan> = HashMap::new();
+
+    /// Uses plans built in code instead of a config on disk.
+    pub fn load_bundle(&mut self, bundle: PlanBundle) -> Result<(), Error> {
+        bundle.validate()?;
+        self.policy = None;
+        self.config = Some(bundle.config());
+        self.install_plans(bundle.plans_with_config())
+    }
+
+    fn prepair_deploy_plan(&mut self) -> Result<(), Error> {
         let config = self.config.clone().unwrap();
+        let mut v: Vec<DeployPlan> = Vec::with_capacity(config.plan_files.len());
         for n in &config.plan_files {
             let path = PathBuf::from(&config.config_dir).join(n);
-            let dp = DeployPlan::from_file(path.to_str().unwrap(), Some(config.clone()));
+            v.push(DeployPlan::from_file(path.to_str().unwrap(), Some(config.clone()))?);
+        }
+        self.install_plans(v)
+    }
+
+    fn install_plans(&mut self, plans: Vec<DeployPlan>) -> Result<(), Error> {
+        let mut m: HashMap<String, DeployPlan> = HashMap::new();
+        for dp in plans {
+            if m.contains_key(&dp.name) {
+                return Err(Error::Failed(format!("Plan `{}` is defined more than once!", &dp.name)));
+            }
             m.insert(dp.name.clone(), dp);
         }
+        check_plan_graph(&m)?;
+        for p in m.values() {
+            p.validate()?;
+        }
+        check_plan_vars(&m)?;
         self.plans = Some(m);
+        Ok(())
+    }
+
+    pub fn config(&self) -> Option<&Config> {
+        self.config.as_ref()
+    }
+
+    /// Names of the loaded plans, sorted.
+    pub fn plan_names(&self) -> Vec<String> {
+        let mut v: Vec<String> = self.plans.iter().flat_map(|m| m.keys().cloned()).collect();
+        v.sort();
+        v
     }
 
-    pub fn run_deploy_plan(&mut self, s: &str) {
-        if self.plans.is_none() {
-            eprintln!("Context not loaded!");
+    /// Every effective setting and whether the profile contributed it.
+    pub fn describe_config(&mut self) -> Result<Vec<ConfigValue>, Error> {
+        match self.config.as_mut() {
+            Some(c) => c.describe(),
+            None => Err(Error::from("Context not loaded!")),
         }
-        let mut m = self.plans.take().unwrap();
-        match m.get_mut(s) {
-            Some(p) => {
-                match p.run() {
-                    Ok(_) => {},
-                    Err(e) => {
-                        eprintln!("Error deplying plan `{}`: {:?}", &p.name, e);
-                    }
-                }
-            }
-            None => {
-                eprintln!("No plan named `{}`!", s);
+    }
+
+    /// Runs the plan named `s`, logging into the config's `log_dir` if set.
+    /// A failing plan still gives a report, with the reason in
+    /// `RunReport::error`. A missing plan, a run lock held by another run, a
+    /// secret which cannot be read or a log directory which cannot be
+    /// created is an `Err`, still written to the audit log. Without a loaded
+    /// context there is no audit log to write to.
+    /// Secret values are masked in the report and in every event.
+    pub fn run_deploy_plan(&mut self, s: &str) -> Result<RunReport, Error> {
+        let config = match (&self.config, &self.plans) {
+            (Some(c), Some(_)) => c,
+            _ => return Err(Error::from("Context not loaded!")),
+        };
+        let mut report = RunReport::new(s);
+        // A missing plan fails like a secret which cannot be read, once
+        // the attempt is logged.
+        let secrets = if self.plans.as_ref().is_some_and(|m| m.contains_key(s)) {
+            config.resolve_secrets(self.vault_key.as_deref())
+        } else {
+            Err(Error::Failed(format!("No plan named `{}`!", s)))
+        };
+        // Written before anything else may fail, so every attempt leaves a
+        // trace even if the run dies on the way.
+        let mut audit = match config.audit_log() {
+            Some(path) => {
+                let mut log = AuditLog::open(&path)
+                    .map_err(|e| Error::Failed(format!("Error opening the audit log `{}`: {}", path.display(), e)))?;
+                let mut vars = config.vars_in_use();
+                vars.extend(config.plan_defaults(s));
+                let vars: Vars = match &secrets {
+                    Ok(secrets) => vars.into_iter().map(|(k, v)| (k, secrets.redact(&v))).collect(),
+                    Err(_) => Vars::new(),
+                };
+                let config_hash = config.hash()?;
+                append(&mut log, AuditEntry::started(&report, &vars, &config_hash))?;
+                Some((log, vars, config_hash))
             },
+            None => None,
+        };
+        let result = secrets.and_then(|secrets| self.run_with_secrets(s, &secrets, &mut report));
+        if let Some((log, vars, config_hash)) = &mut audit {
+            if let Err(e) = &result {
+                report.finish(Err(e.to_string()));
+            }
+            append(log, AuditEntry::finished(&report, vars, config_hash))?;
+        }
+        result.map(|_| report)
+    }
+
+    /// Takes the run locks and runs plan `s` into `report`.
+    fn run_with_secrets(&mut self, s: &str, secrets: &Secrets, report: &mut RunReport) -> Result<(), Error> {
+        let config = self.config.as_ref().unwrap();
+        // Held until the run is over.
+        let mut _locks: Vec<RunLock> = Vec::new();
+        if let Some(locks) = config.locks() {
+            let timeout = self.lock_timeout.unwrap_or_else(|| Duration::from_secs(config.lock_timeout_secs));
+            let mut names = plan_locks(s, self.plans.as_ref().unwrap());
+            if config.global_lock {
+                names.insert(0, GLOBAL_LOCK.to_owned());
+            }
+            for n in names {
+                _locks.push(locks.acquire(&n, s, timeout)?);
+            }
         }
+        let mut observers = self.observers.clone();
+        let log = match config.log_dir() {
+            Some(l) => {
+                let log = Arc::new(l.start(report)
+                    .map_err(|e| Error::Failed(format!("Error creating the run log: {}", e)))?);
+                observers.add(log.clone());
+                Some(log)
+            },
+            None => None,
+        };
+
+        let mut m = self.plans.take().unwrap();
+        let mut p = m.remove(s).unwrap();
+        let observer = Redacting { inner: &observers, secrets };
+        let runner: Arc<dyn CommandRunner> = match &self.policy {
+            Some(policy) => Arc::new(PolicyRunner { inner: self.runner.clone(), policy: policy.clone() }),
+            None => self.runner.clone(),
+        };
+        let mut ctx = RunContext {
+            runner: runner.as_ref(),
+            observer: &observer,
+            secrets,
+            report,
+        };
+        let result = p.run(&secrets.vars(), &mut m, &mut ctx);
+        m.insert(p.name.clone(), p);
         self.plans = Some(m);
+        report.finish(result.map_err(|e| e.to_string()));
+        if !secrets.is_empty() {
+            report.redact(|t| secrets.redact(t));
+        }
+        if let Some(log) = log {
+            // Like the command logs, a missing summary does not fail the run.
+            let _ = log.finish(report);
+        }
+        Ok(())
     }
 }
 
+fn append(log: &mut AuditLog, entry: AuditEntry) -> Result<(), Error> {
+    log.append(entry)
+        .map(|_| ())
+        .map_err(|e| Error::Failed(format!("Error writing the audit log `{}`: {}", log.path().display(), e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Executor;
+    use crate::deploy::builder::{PlanBuilder, TaskBuilder};
+    use crate::event::{Event, Observer};
+    use crate::runner::{CommandOutput, MockRunner, Stream};
+    use crate::testutil::TempDir;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use crate::report::Status;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Event>>);
+
+    impl Observer for Recorder {
+        fn on_event(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_run_report() {
+        let mut executor = Executor::new();
+        executor.load_context(Some("./testresource/run/config.json".to_owned()), None).unwrap();
+        assert_eq!(executor.plan_names(), vec!["Fail".to_owned(), "Ok".to_owned()]);
+
+        let report = executor.run_deploy_plan("Ok").unwrap();
+        assert!(report.success());
+        assert_eq!(report.tasks.len(), 2);
+        assert_eq!(report.tasks[1].item.as_deref(), Some("item=db"));
+        let c = &report.tasks[1].commands[0];
+        assert_eq!(c.args, vec!["hello db".to_owned()]);
+        assert_eq!(c.stdout, "hello db\n");
+        assert_eq!(report.tasks[1].commands[1].exit_code, Some(3));
+    }
+
+    #[test]
+    fn test_run_with_mock_runner() {
+        let runner = Arc::new(MockRunner::new()
+            .respond("rustup show", CommandOutput::success("stable-x86_64-unknown-linux-gnu"))
+            .respond("cargo -V", CommandOutput::success("cargo 1.0.0")));
+        let mut executor = Executor::new().with_runner(runner.clone());
+        executor.load_context(Some("./testresource/config.json".to_owned()), None).unwrap();
+        let report = executor.run_deploy_plan("Plan1").unwrap();
+        assert!(report.success());
+        let called: Vec<String> = runner.calls().iter().map(|c| c.command.clone()).collect();
+        assert_eq!(called, vec!["ls", "echo", "sleep", "rustup", "cargo"]);
+        assert_eq!(report.tasks[0].commands[4].stdout, "cargo 1.0.0");
+    }
+
+    #[test]
+    fn test_mock_runner_failure() {
+        let runner = Arc::new(MockRunner::new()
+            .respond("echo", CommandOutput::success("hello"))
+            .respond("sh -c exit 3", CommandOutput::failure(2, "nope")));
+        let mut executor = Executor::new().with_runner(runner.clone());
+        executor.load_context(Some("./testresource/run/config.json".to_owned()), None).unwrap();
+        let report = executor.run_deploy_plan("Ok").unwrap();
+        assert!(!report.success());
+        assert_eq!(report.tasks[0].commands[1].exit_code, Some(2));
+        assert_eq!(runner.calls().len(), 2);
+    }
+
+    #[test]
+    fn test_run_bundle() {
+        let bundle = PlanBuilder::new("release")
+            .var("version", "2.0")
+            .task(TaskBuilder::new("build").cmd("echo", ["building", "{{ version }}"]))
+            .build()
+            .unwrap();
+        let mut executor = Executor::new();
+        executor.load_bundle(bundle).unwrap();
+        let report = executor.run_deploy_plan("release").unwrap();
+        assert!(report.success());
+        assert_eq!(report.tasks[0].commands[0].stdout, "building 2.0\n");
+    }
+
+    #[test]
+    fn test_unknown_vars_at_load() {
+        let typo = PlanBuilder::new("release")
+            .var("version", "2.0")
+            .task(TaskBuilder::new("build").cmd("echo", ["hi {{ verison }}"]))
+            .build()
+            .unwrap();
+        let e = Executor::new().load_bundle(typo).unwrap_err().to_string();
+        assert_eq!(e, "Plan `release`, task `build`: Command 1: Unknown variable `verison` in `hi {{ verison }}`");
+
+        // Variables given by a caller, and braces of other template languages.
+        let mut vars = crate::deploy::template::Vars::new();
+        vars.insert("target".to_owned(), "{{ version }}".to_owned());
+        vars.insert("version".to_owned(), "{{ version }}".to_owned());
+        let nested = PlanBuilder::new("ship")
+            .task(TaskBuilder::new("push").cmd("echo", ["{{ target }} {{ version }}", "{{.Names}}"]));
+        let bundle = PlanBuilder::new("release").var("version", "2.0").plan(nested, vars).build().unwrap();
+        let mut executor = Executor::new();
+        executor.load_bundle(bundle).unwrap();
+        let report = executor.run_deploy_plan("release").unwrap();
+        assert_eq!(report.tasks[0].commands[0].stdout, "2.0 2.0 {{.Names}}\n");
+    }
+
+    #[test]
+    fn test_nested_plan_defaults() {
+        let ship = PlanBuilder::new("ship")
+            .var("env", "inner")
+            .task(TaskBuilder::new("where").cmd("echo", ["{{ env }}"]));
+        let bundle = PlanBuilder::new("release")
+            .var("env", "outer")
+            .plan(ship, crate::deploy::template::Vars::new())
+            .build()
+            .unwrap();
+        let mut executor = Executor::new();
+        executor.load_bundle(bundle).unwrap();
+        // Same values whether the plan runs alone or as a step.
+        for plan in &["release", "ship"] {
+            let report = executor.run_deploy_plan(plan).unwrap();
+            assert_eq!(report.tasks[0].commands[0].stdout, "inner\n");
+        }
+    }
+
+    #[test]
+    fn test_exit_code_without_expect() {
+        let bundle = PlanBuilder::new("remote")
+            .task(TaskBuilder::new("check").cmd("sh", ["-c", "exit 4"]).cmd("echo", ["not reached"]))
+            .build()
+            .unwrap();
+        let mut executor = Executor::new();
+        executor.load_bundle(bundle).unwrap();
+        let report = executor.run_deploy_plan("remote").unwrap();
+        assert_eq!(report.status, Status::Failed);
+        let c = &report.tasks[0].commands;
+        assert_eq!(c.len(), 1);
+        assert_eq!((c[0].status, c[0].exit_code), (Status::Failed, Some(4)));
+        assert!(c[0].error.as_ref().unwrap().contains("exit code"), "{:?}", c[0].error);
+        assert!(report.render(crate::report::ReportFormat::Junit).contains("<failure"));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_run_as_user() {
+        let missing = PlanBuilder::new("whoami")
+            .task(TaskBuilder::new("id").user("no-such-user-queenbee").cmd("id", ["-u"]))
+            .build();
+        assert!(missing.unwrap_err().to_string().contains("No user `no-such-user-queenbee`"));
+
+        let bundle = PlanBuilder::new("whoami")
+            .task(TaskBuilder::new("id").user("nobody").cmd("id", ["-u"]).cmd("sh", ["-c", "echo $USER"]))
+            .build()
+            .unwrap();
+        let mut executor = Executor::new();
+        executor.load_bundle(bundle).unwrap();
+        let report = executor.run_deploy_plan("whoami").unwrap();
+        if unsafe { libc::geteuid() } == 0 {
+            assert!(report.success(), "{:?}", report.error);
+            assert_eq!(report.tasks[0].commands[0].stdout, "65534\n");
+            assert_eq!(report.tasks[0].commands[1].stdout, "nobody\n");
+        } else {
+            assert!(report.error.unwrap().contains("needs root"));
+        }
+    }
+
+    #[test]
+    fn test_failed_run_report() {
+        let mut executor = Executor::new();
+        executor.load_context(Some("./testresource/run/config.json".to_owned()), None).unwrap();
+        let report = executor.run_deploy_plan("Fail").unwrap();
+        assert_eq!(report.status, Status::Failed);
+        assert!(report.error.as_ref().unwrap().contains("Broken"));
+        let statuses: Vec<(&str, &str, Status)> = report.tasks.iter()
+            .map(|t| (t.plan.as_str(), t.task.as_str(), t.status))
+            .collect();
+        assert_eq!(statuses, vec![
+            ("Ok", "Greet", Status::Success),
+            ("Ok", "Greet", Status::Success),
+            ("Fail", "Broken", Status::Failed),
+            ("Fail", "Broken", Status::Skipped),
+            ("Fail", "Greet", Status::Skipped),
+        ]);
+        assert!(report.tasks[4].commands.is_empty());
+        let xml = report.render(crate::report::ReportFormat::Junit);
+        assert!(xml.contains("name=\"Greet\" time=\"0.000\">\n      <skipped/>"), "{}", xml);
+        let failed = &report.tasks[2].commands[0];
+        assert_eq!(failed.exit_code, Some(1));
+        assert_eq!(failed.stderr, "oops\n");
+        assert!(executor.run_deploy_plan("Missing").is_err());
+    }
+
+    #[test]
+    fn test_observer_events() {
+        let recorder = Arc::new(Recorder::default());
+        let runner = Arc::new(MockRunner::new()
+            .respond("echo", CommandOutput::success("hello\nworld"))
+            .respond("sh -c exit 3", CommandOutput::failure(3, "")));
+        let mut executor = Executor::new().with_runner(runner).with_observer(recorder.clone());
+        executor.load_context(Some("./testresource/run/config.json".to_owned()), None).unwrap();
+        assert!(executor.run_deploy_plan("Ok").unwrap().success());
+
+        let events = recorder.0.lock().unwrap();
+        let kinds: Vec<&str> = events.iter().map(|e| match e {
+            Event::PlanStarted { .. } => "plan",
+            Event::TaskStarted { .. } => "task",
+            Event::CommandStarted { .. } => "command",
+            Event::OutputLine { .. } => "line",
+            Event::CommandFinished { .. } => "command done",
+            Event::TaskFinished { .. } => "task done",
+            Event::TaskSkipped { .. } => "skipped",
+            Event::PlanFinished { .. } => "plan done",
+        }).collect();
+        let item = ["task", "command", "line", "line", "command done", "command", "command done", "task done"];
+        let mut expected = vec!["plan"];
+        expected.extend_from_slice(&item);
+        expected.extend_from_slice(&item);
+        expected.push("plan done");
+        assert_eq!(kinds, expected);
+        match &events[3] {
+            Event::OutputLine { task, item, stream, line, .. } => {
+                assert_eq!((task.as_str(), item.as_deref()), ("Greet", Some("item=web")));
+                assert_eq!((*stream, line.as_str()), (Stream::Stdout, "hello"));
+            },
+            e => panic!("Unexpected event {:?}", e),
+        }
+        match events.last().unwrap() {
+            Event::PlanFinished { plan, status, error, .. } => {
+                assert_eq!((plan.as_str(), *status, error), ("Ok", Status::Success, &None));
+            },
+            e => panic!("Unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_run_log_dir() {
+        let tmp = TempDir::new("executor-logs");
+        let run = std::fs::canonicalize("./testresource/run").unwrap();
+        let config = tmp.join("config.json");
+        std::fs::write(&config, serde_json::json!({
+            "config_dir": run,
+            "command_config_file": "commands.json",
+            "plan_files": ["plans.json"],
+            "taskfiles": {"Greet": "greet.json"},
+            "log_dir": tmp.join("logs"),
+            "log_retention": {"keep_runs": 1},
+        }).to_string()).unwrap();
+
+        let mut executor = Executor::new();
+        executor.load_context(Some(config.to_string_lossy().into_owned()), None).unwrap();
+        let first = executor.run_deploy_plan("Ok").unwrap();
+        let second = executor.run_deploy_plan("Ok").unwrap();
+        let dir = executor.config().unwrap().log_dir().unwrap().run_dir(&second);
+        let mut files: Vec<String> = std::fs::read_dir(&dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec![
+            "001-Greet-item_web-echo.log", "002-Greet-item_web-sh.log",
+            "003-Greet-item_db-echo.log", "004-Greet-item_db-sh.log", "summary.json",
+        ]);
+        let log = std::fs::read_to_string(dir.join("003-Greet-item_db-echo.log")).unwrap();
+        assert!(log.contains(" stdout hello db\n"));
+
+        let logs = executor.config().unwrap().log_dir().unwrap();
+        assert!(logs.run_dir(&first).exists());
+        executor.load_context(Some(config.to_string_lossy().into_owned()), None).unwrap();
+        assert!(!logs.run_dir(&first).exists());
+        assert!(logs.run_dir(&second).exists());
+    }
+
+    #[test]
+    fn test_secrets_redacted() {
+        let recorder = Arc::new(Recorder::default());
+        let mut executor = Executor::new().with_observer(recorder.clone());
+        executor.load_context(Some("./testresource/secret/config.json".to_owned()), None).unwrap();
+        let report = executor.run_deploy_plan("Login").unwrap();
+        assert!(report.success());
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(!json.contains("s3cr3t"));
+        let commands = &report.tasks[0].commands;
+        assert_eq!(commands[0].args, vec!["login --token ***".to_owned()]);
+        assert_eq!(commands[0].stdout, "login --token ***\n");
+        assert_eq!(commands[1].stdout, "token is ***\n");
+        let events = format!("{:?}", recorder.0.lock().unwrap());
+        assert!(events.contains("token is ***"));
+        assert!(!events.contains("s3cr3t"));
+    }
+
+    #[test]
+    fn test_policy() {
+        let tmp = TempDir::new("executor-policy");
+        let run = std::fs::canonicalize("./testresource/run").unwrap();
+        let policy = std::fs::canonicalize("./testresource/policy.json").unwrap();
+        let config = tmp.join("config.json");
+        let write = |policy: &std::path::Path, env: serde_json::Value| {
+            std::fs::write(&config, serde_json::json!({
+                "config_dir": run,
+                "command_config_file": "commands.json",
+                "plan_files": ["plans.json"],
+                "taskfiles": {"Greet": "greet.json"},
+                "policy_file": policy,
+                "env": env,
+            }).to_string()).unwrap();
+        };
+
+        write(&policy, serde_json::json!({}));
+        let mut executor = Executor::new();
+        executor.load_context(Some(config.to_string_lossy().into_owned()), None).unwrap();
+        assert!(executor.run_deploy_plan("Ok").unwrap().success());
+
+        write(&policy, serde_json::json!({"LD_PRELOAD": "evil.so"}));
+        let e = executor.load_context(Some(config.to_string_lossy().into_owned()), None).unwrap_err();
+        assert_eq!(format!("{}", e), "Policy violation: environment variable `LD_PRELOAD` is forbidden");
+
+        let strict = tmp.join("strict.json");
+        std::fs::write(&strict, r#"{"deny_args": ["^echo hello db$"]}"#).unwrap();
+        write(&strict, serde_json::json!({}));
+        executor.load_context(Some(config.to_string_lossy().into_owned()), None).unwrap();
+        let report = executor.run_deploy_plan("Ok").unwrap();
+        assert!(report.tasks[0].error.is_none());
+        assert!(report.tasks[1].error.as_ref().unwrap().starts_with("Policy violation: "));
+        assert!(report.tasks[1].commands[0].error.is_some());
+    }
+
+    #[test]
+    fn test_audit_log() {
+        use crate::audit::AuditEvent;
+        let tmp = TempDir::new("executor-audit");
+        let config = tmp.join("config.json");
+        std::fs::write(&config, serde_json::json!({
+            "config_dir": std::fs::canonicalize("./testresource/run").unwrap(),
+            "command_config_file": "commands.json",
+            "plan_files": ["plans.json", "failplan.json"],
+            "taskfiles": {"Greet": "greet.json", "Broken": "broken.json"},
+            "audit_log": tmp.join("audit.jsonl"),
+            "lock_dir": tmp.join("locks"),
+            "vars": {"region": "eu"},
+        }).to_string()).unwrap();
+        let mut executor = Executor::new();
+        executor.load_context(Some(config.to_string_lossy().into_owned()), None).unwrap();
+        let ok = executor.run_deploy_plan("Ok").unwrap();
+        executor.run_deploy_plan("Fail").unwrap();
+        // A run which never gets going is logged too.
+        let held = executor.config().unwrap().locks().unwrap().acquire("Ok", "someone", Duration::ZERO).unwrap();
+        assert!(executor.run_deploy_plan("Ok").is_err());
+        drop(held);
+        assert!(executor.run_deploy_plan("Missing").is_err());
+
+        assert_eq!(crate::audit::AuditLog::verify(tmp.join("audit.jsonl")).unwrap(), 8);
+        let entries: Vec<crate::audit::AuditEntry> = std::fs::read_to_string(tmp.join("audit.jsonl")).unwrap()
+            .lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let events: Vec<(AuditEvent, Option<Status>)> = entries.iter().map(|e| (e.event, e.status)).collect();
+        assert_eq!(events, vec![
+            (AuditEvent::Started, None), (AuditEvent::Finished, Some(Status::Success)),
+            (AuditEvent::Started, None), (AuditEvent::Finished, Some(Status::Failed)),
+            (AuditEvent::Started, None), (AuditEvent::Finished, Some(Status::Failed)),
+            (AuditEvent::Started, None), (AuditEvent::Finished, Some(Status::Failed)),
+        ]);
+        assert_eq!((entries[0].run_id.as_str(), entries[1].run_id.as_str()), (ok.id.as_str(), ok.id.as_str()));
+        assert_eq!(entries[0].config_hash, entries[3].config_hash);
+        assert_eq!(entries[3].plan, "Fail");
+        assert_eq!(entries[2].vars.get("region").map(String::as_str), Some("eu"));
+        assert!(entries[5].error.as_deref().unwrap().starts_with("Lock `Ok` is held by pid "));
+        assert_eq!((entries[7].plan.as_str(), entries[7].error.as_deref()), ("Missing", Some("No plan named `Missing`!")));
+    }
+
+    #[test]
+    fn test_run_locks() {
+        let tmp = TempDir::new("executor-locks");
+        let config = tmp.join("config.json");
+        std::fs::write(&config, serde_json::json!({
+            "config_dir": std::fs::canonicalize("./testresource/run").unwrap(),
+            "command_config_file": "commands.json",
+            "plan_files": ["plans.json", "failplan.json"],
+            "taskfiles": {"Greet": "greet.json", "Broken": "broken.json"},
+            "lock_dir": tmp.join("locks"),
+            "global_lock": true,
+        }).to_string()).unwrap();
+        let mut executor = Executor::new().with_runner(Arc::new(MockRunner::new()));
+        executor.load_context(Some(config.to_string_lossy().into_owned()), None).unwrap();
+        let locks = executor.config().unwrap().locks().unwrap();
+
+        // `Fail` nests `Ok`, so it needs the lock of `Ok` too.
+        let held = locks.acquire("Ok", "someone", Duration::ZERO).unwrap();
+        let e = executor.run_deploy_plan("Fail").unwrap_err().to_string();
+        assert!(e.starts_with("Lock `Ok` is held by pid "), "{}", e);
+        drop(held);
+        let held = locks.acquire(crate::runlock::GLOBAL_LOCK, "someone", Duration::ZERO).unwrap();
+        assert!(executor.run_deploy_plan("Ok").is_err());
+        drop(held);
+        assert!(executor.run_deploy_plan("Fail").is_ok());
+    }
+}
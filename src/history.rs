@@ -0,0 +1,127 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use crate::report::RunReport;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Finished runs kept as one JSON file per run, named after the run id.
+#[derive(Debug, Clone)]
+pub struct History {
+    dir: PathBuf,
+}
+
+impl History {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        History { dir: dir.as_ref().to_path_buf() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Stores `report`, replacing an earlier record of the same run.
+    pub fn record(&self, report: &RunReport) -> Result<PathBuf, Error> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("{}.json", report.id));
+        fs::write(&path, serde_json::to_string(report)?)?;
+        Ok(path)
+    }
+
+    /// The run with the id `id`, or the only one starting with it.
+    pub fn load(&self, id: &str) -> Result<RunReport, Error> {
+        let ids: Vec<String> = self.ids()?.into_iter().filter(|i| i.starts_with(id)).collect();
+        let found = match ids.iter().find(|i| *i == id) {
+            Some(i) => i,
+            None => match ids.as_slice() {
+                [i] => i,
+                [] => return Err(Error::Failed(format!("No run `{}` in `{}`!", id, self.dir.display()))),
+                _ => return Err(Error::Failed(format!("Run id `{}` is ambiguous, matches {}!", id, ids.join(", ")))),
+            },
+        };
+        self.read(found)
+    }
+
+    /// Every recorded run, oldest first.
+    pub fn list(&self) -> Result<Vec<RunReport>, Error> {
+        let mut v: Vec<RunReport> = Vec::new();
+        for id in self.ids()? {
+            v.push(self.read(&id)?);
+        }
+        v.sort_by_key(|r| r.started);
+        Ok(v)
+    }
+
+    fn read(&self, id: &str) -> Result<RunReport, Error> {
+        let s = fs::read_to_string(self.dir.join(format!("{}.json", id)))?;
+        Ok(serde_json::from_str(&s)?)
+    }
+
+    fn ids(&self) -> Result<Vec<String>, Error> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(e) => e,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut v: Vec<String> = Vec::new();
+        for e in entries {
+            let name = e?.file_name().to_string_lossy().into_owned();
+            if let Some(id) = name.strip_suffix(".json") {
+                v.push(id.to_owned());
+            }
+        }
+        v.sort();
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::History;
+    use crate::report::{RunReport, Status};
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_record_and_load() {
+        let dir = TempDir::new("history");
+        let h = History::new(dir.join("runs"));
+        assert!(h.list().unwrap().is_empty());
+
+        let mut a = RunReport::new("Release");
+        a.finish(Err("boom".to_owned()));
+        let mut b = RunReport::new("Build");
+        b.id = format!("{}x", a.id);
+        b.finish(Ok(()));
+        h.record(&a).unwrap();
+        h.record(&b).unwrap();
+
+        let loaded = h.load(&a.id).unwrap();
+        assert_eq!(loaded.plan, "Release");
+        assert_eq!(loaded.status, Status::Failed);
+        assert_eq!(loaded.started, a.started);
+        assert_eq!(h.load(&b.id).unwrap().plan, "Build");
+        assert!(h.load(&a.id[..4]).is_err());
+        assert!(h.load("nope").is_err());
+        assert_eq!(h.list().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,319 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use std::ffi::CStr;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::process::Command;
+
+/// Ids a command runs with instead of queenbee's own.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Identity {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups, the user's groups when switching users.
+    pub groups: Vec<u32>,
+    /// Name and home directory of the user switched to, `None` when only
+    /// the group is switched.
+    pub name: Option<String>,
+    pub home: Option<String>,
+}
+
+/// One `passwd` entry.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct Passwd {
+    name: String,
+    uid: u32,
+    gid: u32,
+    dir: String,
+}
+
+impl Identity {
+    /// The ids of `user` and `group`, given by name or number. Switching
+    /// user takes the user's primary group unless `group` is given, and the
+    /// user's supplementary groups either way. `None` if both are unset.
+    #[cfg(target_os = "linux")]
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Identity>, Error> {
+        let gid = match group {
+            Some(g) => Some(find_group(g)?),
+            None => None,
+        };
+        match user {
+            Some(u) => {
+                let pw = find_user(u)?;
+                let gid = gid.unwrap_or(pw.gid);
+                let groups = group_list(&pw.name, gid);
+                Ok(Some(Identity { uid: pw.uid, gid, groups, name: Some(pw.name), home: Some(pw.dir) }))
+            },
+            // SAFETY: getuid cannot fail.
+            None => Ok(gid.map(|gid| Identity { uid: unsafe { libc::getuid() }, gid, groups: vec![gid], name: None, home: None })),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Identity>, Error> {
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+        Err(Error::from("Running commands as another user or group is unsupported on this platform!"))
+    }
+
+    /// Fails unless queenbee may take these ids, which needs root unless
+    /// they are queenbee's own already.
+    pub fn check_privileges(&self) -> Result<(), Error> {
+        // SAFETY: geteuid cannot fail.
+        let euid = unsafe { libc::geteuid() };
+        if euid == 0 || self.is_current() {
+            return Ok(());
+        }
+        Err(Error::Failed(format!("queenbee runs as uid {} and needs root to run commands as uid {} gid {}!", euid, self.uid, self.gid)))
+    }
+
+    /// Whether queenbee runs with these user and group ids already.
+    fn is_current(&self) -> bool {
+        // SAFETY: these calls cannot fail.
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        self.uid == euid && self.gid == egid
+    }
+
+    /// Makes the child of `c` switch to these ids right before exec, with
+    /// `HOME`, `USER` and `LOGNAME` set for the user switched to. Nothing
+    /// is switched if they are queenbee's own, the child keeps queenbee's
+    /// supplementary groups and environment then. Variables set on `c`
+    /// afterwards take precedence.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, c: &mut Command) -> Result<(), Error> {
+        use std::io;
+        use std::os::unix::process::CommandExt;
+        if self.is_current() {
+            return Ok(());
+        }
+        if let (Some(name), Some(home)) = (&self.name, &self.home) {
+            c.env("HOME", home).env("USER", name).env("LOGNAME", name);
+        }
+        let id = self.clone();
+        // SAFETY: only async-signal-safe calls, and nothing is allocated
+        // in the child. Groups go first, they cannot be set without root.
+        unsafe {
+            c.pre_exec(move || {
+                if libc::setgroups(id.groups.len() as _, id.groups.as_ptr()) != 0
+                    || libc::setgid(id.gid) != 0
+                    || libc::setuid(id.uid) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _c: &mut Command) -> Result<(), Error> {
+        if self.is_current() {
+            return Ok(());
+        }
+        Err(Error::from("Running commands as another user or group is unsupported on this platform!"))
+    }
+}
+
+/// The name `passwd` has for `uid`, or the uid itself if it has none.
+pub fn user_name(uid: u32) -> String {
+    passwd(|pw, buf, found| unsafe { libc::getpwuid_r(uid, pw, buf.as_mut_ptr(), buf.len(), found) })
+        .map(|pw| pw.name)
+        .unwrap_or_else(|| uid.to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn find_user(user: &str) -> Result<Passwd, Error> {
+    let found = match user.parse::<u32>() {
+        Ok(uid) => passwd(|pw, buf, found| unsafe { libc::getpwuid_r(uid, pw, buf.as_mut_ptr(), buf.len(), found) }),
+        Err(_) => {
+            let name = CString::new(user).map_err(|_| Error::Failed(format!("Invalid user name `{}`!", user)))?;
+            passwd(|pw, buf, found| unsafe { libc::getpwnam_r(name.as_ptr(), pw, buf.as_mut_ptr(), buf.len(), found) })
+        },
+    };
+    found.ok_or_else(|| Error::Failed(format!("No user `{}` on this host!", user)))
+}
+
+#[cfg(target_os = "linux")]
+fn find_group(group: &str) -> Result<u32, Error> {
+    let found = match group.parse::<u32>() {
+        Ok(gid) => group_id(|gr, buf, found| unsafe { libc::getgrgid_r(gid, gr, buf.as_mut_ptr(), buf.len(), found) }),
+        Err(_) => {
+            let name = CString::new(group).map_err(|_| Error::Failed(format!("Invalid group name `{}`!", group)))?;
+            group_id(|gr, buf, found| unsafe { libc::getgrnam_r(name.as_ptr(), gr, buf.as_mut_ptr(), buf.len(), found) })
+        },
+    };
+    found.ok_or_else(|| Error::Failed(format!("No group `{}` on this host!", group)))
+}
+
+/// Runs a `getgr*_r` call like `passwd`, giving the group's id.
+#[cfg(target_os = "linux")]
+fn group_id<F>(mut lookup: F) -> Option<u32>
+where
+    F: FnMut(&mut libc::group, &mut Vec<libc::c_char>, &mut *mut libc::group) -> libc::c_int,
+{
+    let mut buf = vec![0 as libc::c_char; 4096];
+    // SAFETY: getgr*_r write only into `gr` and `buf`, `found` points to
+    // `gr` or is null.
+    unsafe {
+        let mut gr: libc::group = std::mem::zeroed();
+        let mut found: *mut libc::group = std::ptr::null_mut();
+        loop {
+            match lookup(&mut gr, &mut buf, &mut found) {
+                libc::ERANGE => buf.resize(buf.len() * 2, 0),
+                0 if !found.is_null() => return Some(gr.gr_gid),
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Runs a `getpw*_r` call, which fills the entry and points `found` at it,
+/// again with a larger buffer while the entry does not fit.
+fn passwd<F>(mut lookup: F) -> Option<Passwd>
+where
+    F: FnMut(&mut libc::passwd, &mut Vec<libc::c_char>, &mut *mut libc::passwd) -> libc::c_int,
+{
+    let mut buf = vec![0 as libc::c_char; 4096];
+    // SAFETY: the entry's strings point into `buf`, which outlives them.
+    unsafe {
+        let mut pw: libc::passwd = std::mem::zeroed();
+        let mut found: *mut libc::passwd = std::ptr::null_mut();
+        loop {
+            match lookup(&mut pw, &mut buf, &mut found) {
+                libc::ERANGE => buf.resize(buf.len() * 2, 0),
+                0 if !found.is_null() && !pw.pw_name.is_null() => break,
+                _ => return None,
+            }
+        }
+        Some(Passwd {
+            name: cstr(pw.pw_name),
+            uid: pw.pw_uid,
+            gid: pw.pw_gid,
+            dir: cstr(pw.pw_dir),
+        })
+    }
+}
+
+/// The string at `p`, empty if null.
+///
+/// # Safety
+/// `p` must be null or point to a nul terminated string.
+unsafe fn cstr(p: *const libc::c_char) -> String {
+    if p.is_null() { String::new() } else { CStr::from_ptr(p).to_string_lossy().into_owned() }
+}
+
+#[cfg(target_os = "linux")]
+/// `gid` and the groups `user` is a member of.
+fn group_list(user: &str, gid: u32) -> Vec<u32> {
+    let name = match CString::new(user) {
+        Ok(n) => n,
+        Err(_) => return vec![gid],
+    };
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut n = groups.len() as libc::c_int;
+        // SAFETY: at most `n` groups are written, `n` is then set to how
+        // many there are.
+        let r = unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut n) };
+        if r >= 0 {
+            groups.truncate(n as usize);
+            return groups;
+        }
+        groups.resize((n as usize).max(groups.len() * 2), 0);
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::Identity;
+    use std::process::Command;
+
+    #[test]
+    fn test_identity() {
+        let root = Identity::resolve(Some("root"), None).unwrap().unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(root.groups.contains(&0));
+        assert_eq!(Identity::resolve(Some("0"), Some("0")).unwrap().as_ref(), Some(&root));
+        assert_eq!(Identity::resolve(None, None).unwrap(), None);
+        assert_eq!(super::user_name(0), "root");
+        let e = Identity::resolve(Some("no-such-user-queenbee"), None).unwrap_err();
+        assert_eq!(format!("{}", e), "No user `no-such-user-queenbee` on this host!");
+        assert!(Identity::resolve(None, Some("no-such-group-queenbee")).is_err());
+        assert_eq!((root.name.as_deref(), root.home.as_deref()), (Some("root"), Some("/root")));
+        assert_eq!(Identity::resolve(None, Some("0")).unwrap().unwrap().name, None);
+
+        // Running as oneself needs no privileges, like the `deploy` user
+        // running a plan with `user: deploy`.
+        let me = Identity::resolve(Some(&super::user_name(unsafe { libc::geteuid() })), None).unwrap().unwrap();
+        me.check_privileges().unwrap();
+        let mut c = Command::new("id");
+        me.apply(&mut c).unwrap();
+        assert!(c.output().unwrap().status.success());
+
+        if unsafe { libc::geteuid() } != 0 {
+            assert!(root.check_privileges().is_err());
+            return;
+        }
+        let nobody = Identity::resolve(Some("nobody"), None).unwrap().unwrap();
+        nobody.check_privileges().unwrap();
+        let mut c = Command::new("id");
+        nobody.apply(&mut c).unwrap();
+        let out = c.output().unwrap();
+        let id = String::from_utf8_lossy(&out.stdout);
+        assert!(id.starts_with(&format!("uid={}(", nobody.uid)), "{}", id);
+        assert!(!id.contains("(root)"), "{}", id);
+
+        let env = |c: &mut Command| String::from_utf8(c.output().unwrap().stdout).unwrap();
+        let mut c = Command::new("sh");
+        c.args(["-c", "echo $HOME $USER $LOGNAME"]);
+        nobody.apply(&mut c).unwrap();
+        assert_eq!(env(&mut c), format!("{} nobody nobody\n", nobody.home.as_deref().unwrap()));
+        c.env("HOME", "/srv");
+        assert_eq!(env(&mut c), "/srv nobody nobody\n");
+    }
+
+    #[test]
+    fn test_lookup_retries_with_larger_buffer() {
+        let mut sizes = Vec::new();
+        let pw = super::passwd(|pw, buf, found| {
+            sizes.push(buf.len());
+            if buf.len() < 16384 {
+                return libc::ERANGE;
+            }
+            unsafe { libc::getpwuid_r(0, pw, buf.as_mut_ptr(), buf.len(), found) }
+        });
+        assert_eq!(pw.map(|pw| pw.name).as_deref(), Some("root"));
+        assert_eq!(sizes, [4096, 8192, 16384]);
+
+        let gid = super::group_id(|gr, buf, found| {
+            if buf.len() < 8192 {
+                return libc::ERANGE;
+            }
+            unsafe { libc::getgrgid_r(0, gr, buf.as_mut_ptr(), buf.len(), found) }
+        });
+        assert_eq!(gid, Some(0));
+    }
+}
@@ -0,0 +1,228 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use crate::vault::write_private;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// Name of the lockfile, which covers the files in its directory and below.
+pub const LOCK_FILE: &str = "queenbee.lock";
+/// Name of the detached ed25519 signature of the lockfile.
+pub const SIGNATURE_FILE: &str = "queenbee.lock.sig";
+
+/// Key the lockfile must be signed with, once `require_signature` is called.
+static TRUSTED: RwLock<Option<VerifyingKey>> = RwLock::new(None);
+
+thread_local! {
+    /// Files read while `recording`, which are not verified then.
+    static RECORDED: RefCell<Option<Vec<PathBuf>>> = const { RefCell::new(None) };
+}
+
+/// SHA-256 hashes of the config, plan, task and command pool files, keyed
+/// by their path relative to the lockfile.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Lockfile {
+    pub version: u32,
+    pub files: BTreeMap<String, String>,
+}
+
+/// From now on every file read by `read` must be covered by a lockfile
+/// signed with `key`.
+pub fn require_signature(key: VerifyingKey) {
+    *TRUSTED.write().unwrap() = Some(key);
+}
+
+/// Reads a config, plan, task or command pool file. If a lockfile is found
+/// in its directory or a parent, the file must be listed there with the
+/// same hash.
+pub fn read(path: &Path) -> Result<String, Error> {
+    let bytes = fs::read(path)?;
+    let recorded = RECORDED.with(|r| r.borrow_mut().as_mut().map(|v| v.push(path.to_path_buf())).is_some());
+    if !recorded {
+        check(path, &bytes, TRUSTED.read().unwrap().as_ref())?;
+    }
+    String::from_utf8(bytes).map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+/// Runs `f` without verifying anything and returns the files it read, to
+/// be locked by `write_lock`.
+pub fn recording<T>(f: impl FnOnce() -> T) -> (T, Vec<PathBuf>) {
+    RECORDED.with(|r| *r.borrow_mut() = Some(Vec::new()));
+    let v = f();
+    let files = RECORDED.with(|r| r.borrow_mut().take()).unwrap_or_default();
+    (v, files)
+}
+
+fn check(path: &Path, bytes: &[u8], trusted: Option<&VerifyingKey>) -> Result<(), Error> {
+    let canonical = fs::canonicalize(path)?;
+    let dir = match canonical.ancestors().skip(1).find(|d| d.join(LOCK_FILE).is_file()) {
+        Some(d) => d,
+        None if trusted.is_some() => return Err(Error::Integrity(format!("`{}` is not covered by any `{}`", path.display(), LOCK_FILE))),
+        None => return Ok(()),
+    };
+    let lock_path = dir.join(LOCK_FILE);
+    let raw = fs::read(&lock_path)?;
+    if let Some(key) = trusted {
+        verify_signature(&dir.join(SIGNATURE_FILE), &raw, key)?;
+    }
+    let lock: Lockfile = serde_json::from_slice(&raw)?;
+    let name = canonical.strip_prefix(dir).unwrap().to_string_lossy();
+    match lock.files.get(name.as_ref()) {
+        Some(h) if *h == sha256(bytes) => Ok(()),
+        Some(_) => Err(Error::Integrity(format!("`{}` changed since `{}` was written, run `queenbee lock` if the change is wanted", path.display(), lock_path.display()))),
+        None => Err(Error::Integrity(format!("`{}` is not listed in `{}`", path.display(), lock_path.display()))),
+    }
+}
+
+fn verify_signature(path: &Path, lock: &[u8], key: &VerifyingKey) -> Result<(), Error> {
+    let s = fs::read_to_string(path)
+        .map_err(|e| Error::Integrity(format!("Cannot read signature `{}`: {}", path.display(), e)))?;
+    let signature = decode(s.trim())
+        .and_then(|b| Signature::from_slice(&b).ok())
+        .ok_or_else(|| Error::Integrity(format!("`{}` is not an ed25519 signature", path.display())))?;
+    key.verify(lock, &signature)
+        .map_err(|_| Error::Integrity(format!("`{}` does not match the lockfile or the trusted key", path.display())))
+}
+
+/// Writes a lockfile in `dir` with the hash of every file of `files`, all
+/// of which must be inside `dir`, and signs it with `key` if one is given.
+pub fn write_lock(dir: &Path, files: &[PathBuf], key: Option<&SigningKey>) -> Result<PathBuf, Error> {
+    let dir = fs::canonicalize(dir)?;
+    let mut lock = Lockfile { version: 1, files: BTreeMap::new() };
+    for f in files {
+        let canonical = fs::canonicalize(f)?;
+        let name = match canonical.strip_prefix(&dir) {
+            Ok(n) => n.to_string_lossy().into_owned(),
+            Err(_) => return Err(Error::Integrity(format!("`{}` is outside of `{}` and cannot be locked", f.display(), dir.display()))),
+        };
+        lock.files.insert(name, sha256(&fs::read(&canonical)?));
+    }
+    let raw = serde_json::to_string_pretty(&lock)? + "\n";
+    let path = dir.join(LOCK_FILE);
+    fs::write(&path, &raw)?;
+    let sig = dir.join(SIGNATURE_FILE);
+    match key {
+        Some(k) => fs::write(&sig, BASE64.encode(k.sign(raw.as_bytes()).to_bytes()) + "\n")?,
+        // A stale signature would only fail verification.
+        None if sig.exists() => fs::remove_file(&sig)?,
+        None => {},
+    }
+    Ok(path)
+}
+
+/// Reads the base64 ed25519 secret key at `path`, or creates one there
+/// along with its public key in `<path>.pub`.
+pub fn signing_key(path: &Path) -> Result<SigningKey, Error> {
+    if !path.exists() {
+        let key = SigningKey::generate(&mut OsRng);
+        write_private(path, (BASE64.encode(key.to_bytes()) + "\n").as_bytes())?;
+        fs::write(public_key_path(path), BASE64.encode(key.verifying_key().to_bytes()) + "\n")?;
+        return Ok(key);
+    }
+    let b = decode(fs::read_to_string(path)?.trim())
+        .and_then(|b| <[u8; 32]>::try_from(b.as_slice()).ok())
+        .ok_or_else(|| Error::Integrity(format!("`{}` is not an ed25519 secret key", path.display())))?;
+    Ok(SigningKey::from_bytes(&b))
+}
+
+/// Where `signing_key` puts the public key of the secret key at `path`.
+pub fn public_key_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".pub");
+    PathBuf::from(p)
+}
+
+/// Reads the base64 ed25519 public key at `path`.
+pub fn verifying_key(path: &Path) -> Result<VerifyingKey, Error> {
+    decode(fs::read_to_string(path)?.trim())
+        .and_then(|b| <[u8; 32]>::try_from(b.as_slice()).ok())
+        .and_then(|b| VerifyingKey::from_bytes(&b).ok())
+        .ok_or_else(|| Error::Integrity(format!("`{}` is not an ed25519 public key", path.display())))
+}
+
+pub(crate) fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode(s: &str) -> Option<Vec<u8>> {
+    BASE64.decode(s).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn test_lock() {
+        let tmp = TempDir::new("integrity");
+        fs::create_dir_all(tmp.join("plans")).unwrap();
+        let config = tmp.join("config.json");
+        let plan = tmp.join("plans/plan.json");
+        fs::write(&config, "{}").unwrap();
+        fs::write(&plan, r#"{"name": "p"}"#).unwrap();
+
+        let ((), files) = recording(|| {
+            read(&config).unwrap();
+            read(&plan).unwrap();
+        });
+        assert_eq!(files, vec![config.clone(), plan.clone()]);
+        let lock = write_lock(&tmp, &files, None).unwrap();
+        let written: Lockfile = serde_json::from_str(&fs::read_to_string(&lock).unwrap()).unwrap();
+        assert_eq!(written.files.keys().collect::<Vec<_>>(), vec!["config.json", "plans/plan.json"]);
+        assert!(check(&plan, r#"{"name": "p"}"#.as_bytes(), None).is_ok());
+
+        let e = check(&plan, r#"{"name": "q"}"#.as_bytes(), None).unwrap_err();
+        assert!(format!("{}", e).starts_with("Integrity check failed: "), "{}", e);
+        let other = tmp.join("other.json");
+        fs::write(&other, "{}").unwrap();
+        assert!(format!("{}", check(&other, b"{}", None).unwrap_err()).contains("is not listed in"));
+        assert!(write_lock(&tmp.join("plans"), &files, None).is_err());
+
+        let key = signing_key(&tmp.join("key")).unwrap();
+        assert_eq!(signing_key(&tmp.join("key")).unwrap().to_bytes(), key.to_bytes());
+        let trusted = verifying_key(&tmp.join("key.pub")).unwrap();
+        assert!(check(&config, b"{}", Some(&trusted)).is_err());
+        write_lock(&tmp, &files, Some(&key)).unwrap();
+        assert!(check(&config, b"{}", Some(&trusted)).is_ok());
+        let stranger = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        assert!(check(&config, b"{}", Some(&stranger)).is_err());
+
+        // Unsigned again, so a required signature is missing.
+        write_lock(&tmp, &files, None).unwrap();
+        assert!(!tmp.join(SIGNATURE_FILE).exists());
+        assert!(check(&config, b"{}", Some(&trusted)).is_err());
+        assert!(check(&std::env::temp_dir(), b"", Some(&trusted)).is_err());
+    }
+}
@@ -20,9 +20,33 @@
 //! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 //! SOFTWARE.
 //! 
-mod deploy;
-mod err;
-mod config;
+pub mod audit;
+pub mod daemon;
+pub mod deploy;
+pub mod err;
+pub mod config;
+pub mod event;
+pub mod history;
+pub mod identity;
+pub mod limits;
+pub mod integrity;
+pub mod logdir;
+pub mod policy;
 pub mod executor;
+pub mod report;
+pub mod runlock;
+pub mod runner;
+pub mod sandbox;
+pub mod secret;
+pub mod vault;
+#[cfg(test)]
+mod testutil;
 
-use err::Error;
\ No newline at end of file
+pub use config::Config;
+pub use deploy::{CommandSet, DeployPlan, DeployTask, ExecutableCommand};
+pub use deploy::builder::{PlanBuilder, PlanBundle, TaskBuilder};
+pub use err::Error;
+pub use event::{Event, Observer};
+pub use executor::Executor;
+pub use history::History;
+pub use report::RunReport;
\ No newline at end of file
@@ -0,0 +1,179 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use serde::{Serialize, Deserialize};
+use std::process::Command;
+
+/// Resource limits and priority of a command's process, so a runaway
+/// script cannot take the whole host down. Unset fields are inherited
+/// from queenbee.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
+pub struct Limits {
+    /// Address space in MiB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_mb: Option<u64>,
+    /// CPU time in seconds, the process gets SIGXCPU then SIGKILL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_files: Option<u64>,
+    /// Processes of the user running the command, all of them count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processes: Option<u64>,
+    /// Size of core dumps in MiB, 0 disables them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub core_mb: Option<u64>,
+    /// Scheduling priority from -20 (highest) to 19, below 0 needs root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ionice: Option<IoNice>,
+}
+
+/// I/O scheduling of a command, as set by `ionice`.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+pub struct IoNice {
+    pub class: IoClass,
+    /// From 0 (highest) to 7, ignored by the idle class.
+    #[serde(default)]
+    pub level: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+#[cfg(target_os = "linux")]
+const MIB: u64 = 1024 * 1024;
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+/// The resource argument of `setrlimit`, which glibc gives a type of its own.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(all(target_os = "linux", not(target_env = "gnu")))]
+type Resource = libc::c_int;
+
+impl Limits {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(n) = self.nice {
+            if !(-20..=19).contains(&n) {
+                return Err(Error::Failed(format!("Nice value {} is not between -20 and 19!", n)));
+            }
+        }
+        if let Some(io) = self.ionice {
+            if io.level > 7 {
+                return Err(Error::Failed(format!("I/O priority level {} is not between 0 and 7!", io.level)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the child of `c` take these limits right before exec.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, c: &mut Command) -> Result<(), Error> {
+        use std::io;
+        use std::os::unix::process::CommandExt;
+        let mut rlimits: Vec<(Resource, u64)> = Vec::new();
+        let mut add = |resource, v: Option<u64>, unit: u64| {
+            if let Some(v) = v {
+                rlimits.push((resource, v.saturating_mul(unit)));
+            }
+        };
+        add(libc::RLIMIT_AS, self.memory_mb, MIB);
+        add(libc::RLIMIT_CPU, self.cpu_secs, 1);
+        add(libc::RLIMIT_NOFILE, self.open_files, 1);
+        add(libc::RLIMIT_NPROC, self.processes, 1);
+        add(libc::RLIMIT_CORE, self.core_mb, MIB);
+        let nice = self.nice;
+        let ioprio = self.ionice.map(|io| {
+            let class = match io.class {
+                IoClass::Realtime => 1,
+                IoClass::BestEffort => 2,
+                IoClass::Idle => 3,
+            };
+            (class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(io.level)
+        });
+        // SAFETY: only async-signal-safe calls on values prepared above.
+        unsafe {
+            c.pre_exec(move || {
+                for (resource, v) in &rlimits {
+                    let r = libc::rlimit { rlim_cur: *v as libc::rlim_t, rlim_max: *v as libc::rlim_t };
+                    if libc::setrlimit(*resource, &r) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(n) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, n) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(p) = ioprio {
+                    if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, p) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _c: &mut Command) -> Result<(), Error> {
+        Err(Error::from("Resource limits are unsupported on this platform!"))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::{IoClass, IoNice, Limits};
+    use std::process::Command;
+
+    #[test]
+    fn test_limits() {
+        let l: Limits = serde_json::from_str(r#"{"open_files": 32, "cpu_secs": 60, "core_mb": 0, "nice": 5, "ionice": {"class": "best-effort", "level": 6}}"#).unwrap();
+        assert_eq!(l.ionice, Some(IoNice { class: IoClass::BestEffort, level: 6 }));
+        l.validate().unwrap();
+        assert!(Limits { nice: Some(20), ..Limits::default() }.validate().is_err());
+        assert!(Limits { ionice: Some(IoNice { class: IoClass::Idle, level: 8 }), ..Limits::default() }.validate().is_err());
+
+        let mut c = Command::new("sh");
+        c.args(["-c", "ulimit -n; ulimit -t; ulimit -c; nice"]);
+        l.apply(&mut c).unwrap();
+        let out = c.output().unwrap();
+        let lines: Vec<String> = String::from_utf8_lossy(&out.stdout).lines().map(str::to_owned).collect();
+        assert_eq!(lines, ["32", "60", "0", "5"]);
+
+        let mut c = Command::new("sh");
+        c.args(["-c", "x=$(head -c 100000000 /dev/zero | tr '\\0' x); echo ${#x}"]);
+        Limits { memory_mb: Some(64), ..Limits::default() }.apply(&mut c).unwrap();
+        assert!(!c.status().unwrap().success());
+    }
+}
@@ -0,0 +1,343 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use crate::event::{Event, Observer};
+use crate::report::RunReport;
+use crate::runner::Stream;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Name of the file holding the `RunReport` of a run.
+pub const SUMMARY_FILE: &str = "summary.json";
+
+/// Limits on the runs kept in a log directory, checked when a config is
+/// loaded. Unset limits are not enforced.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct Retention {
+    /// Runs kept per plan, the newest ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_runs: Option<usize>,
+    /// Runs older than this many days are removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_days: Option<u64>,
+    /// Oldest runs are removed until all of them fit in this many megabytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_total_mb: Option<u64>,
+}
+
+/// Root of the run logs: `<plan>/<timestamp>-<run-id>/` per run, holding
+/// `summary.json` and one log file per command.
+#[derive(Debug, Clone)]
+pub struct LogDir {
+    root: PathBuf,
+    retention: Retention,
+}
+
+/// A run directory found on disk.
+#[derive(Debug, Clone)]
+struct RunDir {
+    path: PathBuf,
+    plan: String,
+    modified: SystemTime,
+    size: u64,
+}
+
+impl LogDir {
+    pub fn new<P: AsRef<Path>>(root: P, retention: Retention) -> Self {
+        LogDir { root: root.as_ref().to_path_buf(), retention }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where the run of `report` logs to, not created yet.
+    pub fn run_dir(&self, report: &RunReport) -> PathBuf {
+        let ts = DateTime::<Utc>::from(report.started).format("%Y%m%dT%H%M%S%.3fZ");
+        self.root.join(sanitize(&report.plan)).join(format!("{}-{}", ts, report.id))
+    }
+
+    /// Creates the directory of the run of `report` and the observer
+    /// writing the command logs into it.
+    pub fn start(&self, report: &RunReport) -> Result<RunLog, Error> {
+        let dir = self.run_dir(report);
+        fs::create_dir_all(&dir)?;
+        Ok(RunLog { dir, files: Mutex::new((0, HashMap::new())) })
+    }
+
+    /// Removes the runs going over the retention limits, returning their
+    /// directories.
+    pub fn prune(&self) -> Result<Vec<PathBuf>, Error> {
+        self.prune_at(SystemTime::now())
+    }
+
+    fn prune_at(&self, now: SystemTime) -> Result<Vec<PathBuf>, Error> {
+        let r = &self.retention;
+        // Oldest first, the directory names start with the time of the run.
+        let mut runs = self.runs()?;
+        let mut removed: Vec<RunDir> = Vec::new();
+        if let Some(days) = r.max_age_days {
+            let max = Duration::from_secs(days * 24 * 60 * 60);
+            let (old, kept): (Vec<RunDir>, Vec<RunDir>) = runs.into_iter()
+                .partition(|d| now.duration_since(d.modified).unwrap_or_default() > max);
+            removed.extend(old);
+            runs = kept;
+        }
+        if let Some(keep) = r.keep_runs {
+            let mut seen: HashMap<String, usize> = HashMap::new();
+            let mut kept: Vec<RunDir> = Vec::new();
+            for d in runs.into_iter().rev() {
+                let n = seen.entry(d.plan.clone()).or_insert(0);
+                *n += 1;
+                if *n > keep {
+                    removed.push(d);
+                } else {
+                    kept.insert(0, d);
+                }
+            }
+            runs = kept;
+        }
+        if let Some(mb) = r.max_total_mb {
+            let mut total: u64 = runs.iter().map(|d| d.size).sum();
+            for d in runs {
+                if total <= mb * 1024 * 1024 {
+                    break;
+                }
+                total -= d.size;
+                removed.push(d);
+            }
+        }
+        let mut paths = Vec::with_capacity(removed.len());
+        for d in removed {
+            fs::remove_dir_all(&d.path)?;
+            paths.push(d.path);
+        }
+        Ok(paths)
+    }
+
+    fn runs(&self) -> Result<Vec<RunDir>, Error> {
+        let mut v: Vec<RunDir> = Vec::new();
+        let plans = match fs::read_dir(&self.root) {
+            Ok(p) => p,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(v),
+            Err(e) => return Err(e.into()),
+        };
+        for p in plans {
+            let p = p?;
+            if !p.file_type()?.is_dir() {
+                continue;
+            }
+            for r in fs::read_dir(p.path())? {
+                let r = r?;
+                if !r.file_type()?.is_dir() {
+                    continue;
+                }
+                v.push(RunDir {
+                    path: r.path(),
+                    plan: p.file_name().to_string_lossy().into_owned(),
+                    modified: r.metadata()?.modified()?,
+                    size: dir_size(&r.path())?,
+                });
+            }
+        }
+        v.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
+        Ok(v)
+    }
+}
+
+/// Plan, task and item label of a running task item.
+type ItemKey = (String, String, Option<String>);
+
+/// Writes the output of every command of one run to its own file, lines
+/// prefixed with the time and the stream they came from.
+pub struct RunLog {
+    dir: PathBuf,
+    /// Commands started so far and the open log of each running task item.
+    files: Mutex<(usize, HashMap<ItemKey, File>)>,
+}
+
+impl RunLog {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes `summary.json` once the run is over.
+    pub fn finish(&self, report: &RunReport) -> Result<(), Error> {
+        fs::write(self.dir.join(SUMMARY_FILE), serde_json::to_string_pretty(report)?)?;
+        Ok(())
+    }
+
+    fn write(&self, key: ItemKey, line: String) {
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(f) = files.1.get_mut(&key) {
+            // A full disk must not stop the run.
+            let _ = writeln!(f, "{} {}", now(), line);
+        }
+    }
+}
+
+impl Observer for RunLog {
+    fn on_event(&self, event: &Event) {
+        match event {
+            Event::CommandStarted { plan, task, item, command, args } => {
+                let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+                files.0 += 1;
+                let mut name = format!("{:03}-{}", files.0, task);
+                if let Some(i) = item {
+                    name.push('-');
+                    name.push_str(i);
+                }
+                name.push('-');
+                name.push_str(command);
+                let path = self.dir.join(format!("{}.log", sanitize(&name)));
+                if let Ok(mut f) = File::create(path) {
+                    let _ = writeln!(f, "{} start {} {:?}", now(), command, args);
+                    files.1.insert((plan.clone(), task.clone(), item.clone()), f);
+                }
+            },
+            Event::OutputLine { plan, task, item, stream, line, .. } => {
+                let s = match stream {
+                    Stream::Stdout => "stdout",
+                    Stream::Stderr => "stderr",
+                };
+                self.write((plan.clone(), task.clone(), item.clone()), format!("{} {}", s, line));
+            },
+            Event::CommandFinished { plan, task, item, status, exit_code, duration, error, .. } => {
+                let key = (plan.clone(), task.clone(), item.clone());
+                let exit = exit_code.map_or("none".to_owned(), |c| c.to_string());
+                self.write(key.clone(), format!("finish {:?} exit code {} after {:.3}s", status, exit, duration.as_secs_f64()));
+                if let Some(e) = error {
+                    for l in e.lines() {
+                        self.write(key.clone(), format!("error {}", l));
+                    }
+                }
+                self.files.lock().unwrap_or_else(|e| e.into_inner()).1.remove(&key);
+            },
+            _ => {},
+        }
+    }
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Keeps names usable as file names.
+fn sanitize(s: &str) -> String {
+    s.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' }).collect()
+}
+
+fn dir_size(dir: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+    for e in fs::read_dir(dir)? {
+        let e = e?;
+        let m = e.metadata()?;
+        size += if m.is_dir() { dir_size(&e.path())? } else { m.len() };
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LogDir, Retention, SUMMARY_FILE};
+    use crate::event::{Event, Observer};
+    use crate::report::{RunReport, Status};
+    use crate::runner::Stream;
+    use std::fs;
+    use crate::testutil::TempDir;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_run_log() {
+        let root = TempDir::new("logs");
+        let logs = LogDir::new(&root, Retention::default());
+        let report = RunReport::new("Re lease");
+        let log = logs.start(&report).unwrap();
+        assert!(log.dir().starts_with(root.join("Re_lease")));
+        assert!(log.dir().file_name().unwrap().to_string_lossy().ends_with(&format!("-{}", report.id)));
+
+        let item = Some("item=web".to_owned());
+        log.on_event(&Event::CommandStarted {
+            plan: "Re lease".to_owned(), task: "deploy".to_owned(), item: item.clone(),
+            command: "sh".to_owned(), args: vec!["-c".to_owned()],
+        });
+        for (stream, line) in &[(Stream::Stdout, "hello"), (Stream::Stderr, "oops")] {
+            log.on_event(&Event::OutputLine {
+                plan: "Re lease".to_owned(), task: "deploy".to_owned(), item: item.clone(),
+                command: "sh".to_owned(), stream: *stream, line: line.to_string(),
+            });
+        }
+        log.on_event(&Event::CommandFinished {
+            plan: "Re lease".to_owned(), task: "deploy".to_owned(), item: item.clone(),
+            command: "sh".to_owned(), args: Vec::new(), status: Status::Failed,
+            exit_code: Some(2), duration: Duration::from_millis(5), error: Some("bad".to_owned()),
+        });
+        log.finish(&report).unwrap();
+
+        let s = fs::read_to_string(log.dir().join("001-deploy-item_web-sh.log")).unwrap();
+        let lines: Vec<&str> = s.lines().map(|l| l.split_once(' ').unwrap().1).collect();
+        assert_eq!(lines, vec![
+            "start sh [\"-c\"]",
+            "stdout hello",
+            "stderr oops",
+            "finish Failed exit code 2 after 0.005s",
+            "error bad",
+        ]);
+        assert!(s.starts_with(&format!("{}", chrono::Utc::now().format("%Y-"))));
+        assert!(log.dir().join(SUMMARY_FILE).exists());
+    }
+
+    #[test]
+    fn test_prune() {
+        let root = TempDir::new("prune");
+        let make = |plan: &str, name: &str, size: usize| {
+            let d = root.join(plan).join(name);
+            fs::create_dir_all(&d).unwrap();
+            fs::write(d.join("001-t-echo.log"), vec![b'x'; size]).unwrap();
+        };
+        make("a", "20260101T000000.000Z-1", 10);
+        make("a", "20260102T000000.000Z-2", 10);
+        make("a", "20260103T000000.000Z-3", 10);
+        make("b", "20260101T120000.000Z-4", 10);
+
+        let logs = LogDir::new(&root, Retention { keep_runs: Some(2), ..Retention::default() });
+        let removed = logs.prune().unwrap();
+        assert_eq!(removed, vec![root.join("a").join("20260101T000000.000Z-1")]);
+        assert_eq!(logs.runs().unwrap().len(), 3);
+
+        let logs = LogDir::new(&root, Retention { max_total_mb: Some(0), ..Retention::default() });
+        assert_eq!(logs.prune().unwrap().len(), 3);
+
+        make("a", "20260104T000000.000Z-5", 10);
+        let logs = LogDir::new(&root, Retention { max_age_days: Some(1), ..Retention::default() });
+        assert!(logs.prune().unwrap().is_empty());
+        let later = SystemTime::now() + Duration::from_secs(2 * 24 * 60 * 60);
+        assert_eq!(logs.prune_at(later).unwrap().len(), 1);
+    }
+}
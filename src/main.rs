@@ -21,20 +21,449 @@
 //! SOFTWARE.
 //! 
 use structopt::StructOpt;
+use queenbee::audit::AuditLog;
+use queenbee::config::Config;
+use queenbee::daemon::Daemon;
 use queenbee::executor:: Executor;
+use queenbee::event::{ConsoleObserver, JsonlObserver};
+use queenbee::integrity;
+use queenbee::report::{ReportFormat, RunReport};
+use queenbee::vault::Vault;
+use chrono::{DateTime, Local};
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "queenbee", about = "Usage of queenbee")]
 struct Opt {
-    #[structopt(short = "c", long = "config", help = "Config file path")]
+    #[structopt(short = "c", long = "config", global = true, help = "Config file path")]
     config: Option<String>,
+    #[structopt(short = "p", long = "profile", env = "QUEENBEE_PROFILE", global = true, help = "Profile to apply on the config")]
+    profile: Option<String>,
+    #[structopt(short = "o", long = "output", global = true, default_value = "human", possible_values = &["human", "jsonl"], help = "Output format, `jsonl` writes run events to stdout and logs to stderr")]
+    output: Output,
+    #[structopt(long = "report", global = true, number_of_values = 1, parse(try_from_str = parse_report), help = "Also write the run report as `junit=<path>`, `markdown=<path>` or `html=<path>`, may be repeated")]
+    reports: Vec<(ReportFormat, String)>,
+    #[structopt(long = "secrets-key-file", env = "QUEENBEE_SECRETS_KEY_FILE", global = true, help = "File whose contents unlock the secrets vault instead of a passphrase")]
+    secrets_key_file: Option<String>,
+    #[structopt(long = "lock-timeout", global = true, help = "Seconds to wait for a run lock held by another run, instead of the config's `lock_timeout_secs`")]
+    lock_timeout: Option<u64>,
+    #[structopt(long = "require-signature", global = true, requires = "trusted-key", help = "Refuse config, plan, task and command files not covered by a lockfile signed with --trusted-key")]
+    require_signature: bool,
+    #[structopt(long = "trusted-key", env = "QUEENBEE_TRUSTED_KEY", global = true, help = "Public key file the lockfile must be signed with")]
+    trusted_key: Option<String>,
     #[structopt(help = "Plan to execute")]
-    plan: String
+    plan: Option<String>,
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Cmd {
+    #[structopt(about = "Execute a plan, same as `queenbee <plan>`")]
+    Run {
+        #[structopt(help = "Plan to execute")]
+        plan: String,
+    },
+    #[structopt(about = "Run the plans on the config's `schedules` until stopped, reloading the config on SIGHUP")]
+    Daemon,
+    #[structopt(about = "Show the effective config and where each value comes from")]
+    Show,
+    #[structopt(about = "Render the report of a recorded run, or list the recorded runs")]
+    Report {
+        #[structopt(help = "Id of the run, or a unique prefix of it")]
+        run_id: Option<String>,
+        #[structopt(long = "format", default_value = "html", possible_values = &["html", "junit", "markdown"], help = "Report format")]
+        format: ReportFormat,
+        #[structopt(long = "out", help = "File to write the report to, stdout if not given")]
+        out: Option<String>,
+    },
+    #[structopt(about = "Manage the encrypted secrets vault")]
+    Secrets(SecretsCmd),
+    #[structopt(about = "Check the audit log")]
+    Audit(AuditCmd),
+    #[structopt(about = "Write the lockfile with the hashes of the config, plan, task and command files")]
+    Lock {
+        #[structopt(long = "sign-key", help = "Secret key file to sign the lockfile with, created with its `.pub` file if missing")]
+        sign_key: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum SecretsCmd {
+    #[structopt(about = "Store a secret, read from stdin or a prompt if no value is given")]
+    Set {
+        name: String,
+        value: Option<String>,
+    },
+    #[structopt(about = "Print a secret")]
+    Get {
+        name: String,
+    },
+    #[structopt(about = "List the names of the secrets")]
+    List,
+    #[structopt(about = "Remove a secret")]
+    Rm {
+        name: String,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum AuditCmd {
+    #[structopt(about = "Check that no entry of the audit log was changed, removed or reordered")]
+    Verify {
+        #[structopt(long = "file", help = "Audit log to check instead of the config's `audit_log`")]
+        file: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    Human,
+    Jsonl,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "human" => Ok(Output::Human),
+            "jsonl" => Ok(Output::Jsonl),
+            _ => Err(format!("Unknown output format `{}`", s)),
+        }
+    }
+}
+
+fn parse_report(s: &str) -> Result<(ReportFormat, String), String> {
+    match s.split_once('=') {
+        Some((f, path)) if !path.is_empty() => Ok((f.parse().map_err(|e| format!("{}", e))?, path.to_owned())),
+        _ => Err(format!("Expect `<format>=<path>`, got `{}`", s)),
+    }
 }
 
 fn main() {
     let opt = Opt::from_args();
-    let mut executor = Executor::new();
-    executor.load_context(opt.config);
-    executor.run_deploy_plan(&opt.plan);
+    let mut executor = new_executor(opt.output, opt.lock_timeout);
+    if opt.require_signature {
+        match integrity::verifying_key(Path::new(opt.trusted_key.as_deref().unwrap_or_default())) {
+            Ok(k) => integrity::require_signature(k),
+            Err(e) => {
+                eprintln!("Error reading trusted key: {}", e);
+                exit(1);
+            }
+        }
+    }
+    let (config, profile) = (opt.config, opt.profile);
+    let key_file = opt.secrets_key_file;
+    // The vault and the audit log only need the config, a plan which fails
+    // to load must not lock them away.
+    let cmd = match opt.cmd {
+        Some(Cmd::Secrets(cmd)) => return secrets(cmd, &load_config(config, profile).vault_path(), key_file.as_deref()),
+        Some(Cmd::Audit(AuditCmd::Verify { file })) => return audit_verify(file, config, profile),
+        cmd => cmd,
+    };
+    let (loaded, files) = match cmd {
+        Some(Cmd::Lock { .. }) => integrity::recording(|| executor.load_context(config.clone(), profile.clone())),
+        _ => (executor.load_context(config.clone(), profile.clone()), Vec::new()),
+    };
+    if let Err(e) = loaded {
+        eprintln!("Error loading config: {}", e);
+        exit(1);
+    }
+    let vault = executor.config().map(|c| c.vault_path()).unwrap_or_default();
+    match (cmd, opt.plan) {
+        (Some(Cmd::Run { plan }), _) | (None, Some(plan)) => {
+            if vault.exists() {
+                executor = executor.with_vault_key(vault_key(key_file.as_deref(), &vault));
+            }
+            run(&mut executor, &plan, &opt.reports)
+        },
+        (Some(Cmd::Daemon), _) => {
+            let key = if vault.exists() { Some(vault_key(key_file.as_deref(), &vault)) } else { None };
+            daemon(opt.output, opt.lock_timeout, key, config, profile)
+        },
+        (Some(Cmd::Secrets(_)), _) | (Some(Cmd::Audit(_)), _) => unreachable!("handled before loading the context"),
+        (Some(Cmd::Show), _) => show(&mut executor),
+        (Some(Cmd::Lock { sign_key }), _) => lock(&files, sign_key.as_deref()),
+        (Some(Cmd::Report { run_id, format, out }), _) => report(&executor, run_id, format, out),
+        (None, None) => {
+            Opt::clap().print_help().unwrap();
+            println!();
+            exit(1);
+        }
+    }
+}
+
+/// Loads only the config, for the subcommands which need no plans.
+fn load_config(config: Option<String>, profile: Option<String>) -> Config {
+    Config::new(config).and_then(|c| c.with_profile(profile)).unwrap_or_else(|e| {
+        eprintln!("Error loading config: {}", e);
+        exit(1);
+    })
+}
+
+fn new_executor(output: Output, lock_timeout: Option<u64>) -> Executor {
+    let executor = match output {
+        Output::Human => Executor::new().with_observer(Arc::new(ConsoleObserver::new())),
+        Output::Jsonl => Executor::new()
+            .with_observer(Arc::new(ConsoleObserver::stderr()))
+            .with_observer(Arc::new(JsonlObserver::new(io::stdout()))),
+    };
+    match lock_timeout {
+        Some(secs) => executor.with_lock_timeout(Duration::from_secs(secs)),
+        None => executor,
+    }
+}
+
+fn daemon(output: Output, lock_timeout: Option<u64>, vault_key: Option<Vec<u8>>, config: Option<String>, profile: Option<String>) {
+    let make = move || {
+        let executor = new_executor(output, lock_timeout);
+        match &vault_key {
+            Some(k) => executor.with_vault_key(k.clone()),
+            None => executor,
+        }
+    };
+    let result = Daemon::new(make, config, profile).and_then(|d| d.run());
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(executor: &mut Executor, plan: &str, reports: &[(ReportFormat, String)]) {
+    match executor.run_deploy_plan(plan) {
+        Ok(report) => {
+            if let Some(h) = executor.config().and_then(|c| c.history()) {
+                match h.record(&report) {
+                    Ok(_) => eprintln!("Run `{}` recorded in `{}`", report.id, h.dir().display()),
+                    Err(e) => eprintln!("Error recording run `{}`: {}", report.id, e),
+                }
+            }
+            write_reports(&report, reports);
+            if let Some(e) = &report.error {
+                eprintln!("Error deploying plan `{}`: {}", plan, e);
+                exit(1);
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+fn write_reports(report: &RunReport, reports: &[(ReportFormat, String)]) {
+    let mut failed = false;
+    for (format, path) in reports {
+        if let Err(e) = fs::write(path, report.render(*format)) {
+            eprintln!("Error writing report `{}`: {}", path, e);
+            failed = true;
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+fn show(executor: &mut Executor) {
+    match executor.config().and_then(|c| c.profile_name()) {
+        Some(p) => println!("profile: {}", p),
+        None => println!("profile: <none>"),
+    }
+    for p in executor.plan_names() {
+        println!("plan: {}", p);
+    }
+    match executor.describe_config() {
+        Ok(values) => {
+            for v in values {
+                println!("{} = {}    ({})", v.key, v.value, v.source);
+            }
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+fn report(executor: &Executor, run_id: Option<String>, format: ReportFormat, out: Option<String>) {
+    let history = match executor.config().and_then(|c| c.history()) {
+        Some(h) => h,
+        None => {
+            eprintln!("No history directory, set `history_dir` in the config!");
+            exit(1);
+        }
+    };
+    let run_id = match run_id {
+        Some(id) => id,
+        None => {
+            match history.list() {
+                Ok(runs) => {
+                    for r in runs {
+                        println!("{}  {}  {:?}  {:.3}s  {}", r.id, DateTime::<Local>::from(r.started).format("%Y-%m-%d %H:%M:%S"), r.status, r.duration.as_secs_f64(), r.plan);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+            return;
+        }
+    };
+    let rendered = match history.load(&run_id) {
+        Ok(r) => r.render(format),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    match out {
+        Some(path) => {
+            if let Err(e) = fs::write(&path, rendered) {
+                eprintln!("Error writing report `{}`: {}", path, e);
+                exit(1);
+            }
+        },
+        None => print!("{}", rendered),
+    }
+}
+
+/// Checks the audit log at `file`, or the config's one when not given.
+fn audit_verify(file: Option<String>, config: Option<String>, profile: Option<String>) {
+    let path = match file.map(PathBuf::from).or_else(|| load_config(config, profile).audit_log()) {
+        Some(p) => p,
+        None => {
+            eprintln!("No audit log, set `audit_log` in the config!");
+            exit(1);
+        }
+    };
+    match AuditLog::verify(&path) {
+        Ok(n) => println!("`{}`: {} entries, chain intact", path.display(), n),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// Locks `files`, the first of which is the config, in the config's
+/// directory.
+fn lock(files: &[PathBuf], sign_key: Option<&str>) {
+    let key = sign_key.map(|k| {
+        let existed = Path::new(k).exists();
+        let key = integrity::signing_key(Path::new(k)).unwrap_or_else(|e| {
+            eprintln!("Error reading signing key: {}", e);
+            exit(1);
+        });
+        if !existed {
+            eprintln!("New signing key `{}`, trust `{}` to verify", k, integrity::public_key_path(Path::new(k)).display());
+        }
+        key
+    });
+    let dir = files.first().and_then(|f| f.parent()).unwrap_or_else(|| Path::new("."));
+    match integrity::write_lock(dir, files, key.as_ref()) {
+        Ok(path) => println!("Wrote `{}`", path.display()),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// The contents of `key_file` without a trailing line break, or a
+/// passphrase asked on the terminal, twice for a new vault.
+fn vault_key(key_file: Option<&str>, vault: &Path) -> Vec<u8> {
+    if let Some(f) = key_file {
+        return match fs::read(f) {
+            Ok(mut k) => {
+                while k.last().is_some_and(|c| *c == b'\n' || *c == b'\r') {
+                    k.pop();
+                }
+                k
+            },
+            Err(e) => {
+                eprintln!("Error reading key file `{}`: {}", f, e);
+                exit(1);
+            }
+        };
+    }
+    if !io::stdin().is_terminal() {
+        eprintln!("Vault `{}` needs --secrets-key-file when not run from a terminal!", vault.display());
+        exit(1);
+    }
+    let ask = |prompt: &str| rpassword::prompt_password(prompt).unwrap_or_else(|e| {
+        eprintln!("Error reading passphrase: {}", e);
+        exit(1);
+    });
+    let pass = ask("Vault passphrase: ");
+    if !vault.exists() && ask("Repeat passphrase: ") != pass {
+        eprintln!("Passphrases do not match!");
+        exit(1);
+    }
+    pass.into_bytes()
+}
+
+fn secrets(cmd: SecretsCmd, path: &Path, key_file: Option<&str>) {
+    if !path.exists() && !matches!(cmd, SecretsCmd::Set { .. }) {
+        eprintln!("No vault at `{}` yet, add a secret with `queenbee secrets set`", path.display());
+        exit(1);
+    }
+    let key = vault_key(key_file, path);
+    let mut vault = Vault::open(path, &key).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
+    match cmd {
+        SecretsCmd::Set { name, value } => {
+            let value = match value {
+                Some(v) => v,
+                None if io::stdin().is_terminal() => rpassword::prompt_password(format!("Value of `{}`: ", name)).unwrap_or_else(|e| {
+                    eprintln!("Error reading value: {}", e);
+                    exit(1);
+                }),
+                None => {
+                    let mut v = String::new();
+                    if let Err(e) = io::stdin().read_to_string(&mut v) {
+                        eprintln!("Error reading value: {}", e);
+                        exit(1);
+                    }
+                    v.trim_end_matches(&['\n', '\r'][..]).to_owned()
+                },
+            };
+            vault.set(&name, &value);
+        },
+        SecretsCmd::Get { name } => {
+            match vault.get(&name) {
+                Some(v) => println!("{}", v),
+                None => {
+                    eprintln!("No secret `{}` in the vault!", name);
+                    exit(1);
+                }
+            }
+            return;
+        },
+        SecretsCmd::List => {
+            for n in vault.names() {
+                println!("{}", n);
+            }
+            return;
+        },
+        SecretsCmd::Rm { name } => {
+            if !vault.remove(&name) {
+                eprintln!("No secret `{}` in the vault!", name);
+                exit(1);
+            }
+        },
+    }
+    if let Err(e) = vault.save() {
+        eprintln!("Error saving vault `{}`: {}", path.display(), e);
+        exit(1);
+    }
 }
@@ -0,0 +1,232 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use crate::deploy::{CommandSet, ExecutableCommand};
+use crate::deploy::template::placeholders;
+use crate::integrity;
+use crate::runner::{CommandOutput, CommandRunner, LineSink};
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Restrictions on what commands may run, read from `Config::policy_file`.
+/// Empty lists do not restrict anything.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Policy {
+    /// Absolute paths of the executables allowed to run, commands given by
+    /// name are looked up in `PATH` first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    /// Regular expressions no command line (`command arg1 arg2...`) may
+    /// match.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny_args: Vec<String>,
+    /// Environment variables commands may not be given.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forbid_env: Vec<String>,
+    #[serde(skip_serializing, skip_deserializing)]
+    deny: Vec<Regex>,
+}
+
+impl Policy {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Policy, Error> {
+        let path = path.as_ref();
+        let s = integrity::read(path)
+            .map_err(|e| Error::Failed(format!("Cannot read policy `{}`: {}", path.display(), e)))?;
+        let mut p: Policy = serde_json::from_str(&s)?;
+        p.compile()?;
+        Ok(p)
+    }
+
+    fn compile(&mut self) -> Result<(), Error> {
+        self.deny = Vec::with_capacity(self.deny_args.len());
+        for d in &self.deny_args {
+            self.deny.push(Regex::new(d).map_err(|e| Error::Failed(format!("Invalid pattern `{}` in policy: {}", d, e)))?);
+        }
+        Ok(())
+    }
+
+    /// Checks everything known before running: the environment of the
+    /// config and every command whose executable and arguments contain no
+    /// template placeholders.
+    pub fn validate(&self, commands: &CommandSet, env: &HashMap<String, String>) -> Result<(), Error> {
+        self.check_env(env)?;
+        for c in commands.as_commands() {
+            if placeholders(&c.command).is_empty() {
+                self.check_executable(&c, env)?;
+            }
+            if c.args.iter().all(|a| placeholders(a).is_empty()) && c.with_items.is_empty() {
+                self.check_args(&c)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a concrete command about to run with `env`.
+    pub fn check(&self, c: &ExecutableCommand, env: &HashMap<String, String>) -> Result<(), Error> {
+        self.check_env(env)?;
+        self.check_executable(c, env)?;
+        self.check_args(c)
+    }
+
+    /// Commands inherit the environment of queenbee too, so it is checked
+    /// along with `env`.
+    fn check_env(&self, env: &HashMap<String, String>) -> Result<(), Error> {
+        for k in &self.forbid_env {
+            if env.contains_key(k) {
+                return Err(Error::Policy(format!("environment variable `{}` is forbidden", k)));
+            }
+            if env::var_os(k).is_some() {
+                return Err(Error::Policy(format!("environment variable `{}` is forbidden, unset it before running queenbee", k)));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_executable(&self, c: &ExecutableCommand, env: &HashMap<String, String>) -> Result<(), Error> {
+        if self.allow.is_empty() {
+            return Ok(());
+        }
+        let path = match resolve(&c.command, env) {
+            Some(p) => p,
+            None => return Err(Error::Policy(format!("`{}` (command {}) is not found, so not allowed", c.command, c.idx))),
+        };
+        // Either the path as found or the file it points to may be listed.
+        let real = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        let allowed = self.allow.iter().any(|a| {
+            let a = Path::new(a);
+            a == path || a == real || fs::canonicalize(a).is_ok_and(|a| a == real)
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::Policy(format!("`{}` (command {}) is not in the allowlist", path.display(), c.idx)))
+        }
+    }
+
+    fn check_args(&self, c: &ExecutableCommand) -> Result<(), Error> {
+        let mut line = c.command.clone();
+        for a in &c.args {
+            line.push(' ');
+            line.push_str(a);
+        }
+        match self.deny.iter().find(|r| r.is_match(&line)) {
+            Some(r) => Err(Error::Policy(format!("command {} matches the denied pattern `{}`", c.idx, r.as_str()))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where `command` would be run from: itself if it has a slash, otherwise
+/// the first match in the `PATH` of `env` or of queenbee.
+fn resolve(command: &str, env: &HashMap<String, String>) -> Option<PathBuf> {
+    if command.contains('/') {
+        let p = PathBuf::from(command);
+        return if p.is_file() { fs::canonicalize(&p).ok().or(Some(p)) } else { None };
+    }
+    let path = env.get("PATH").cloned().or_else(|| env::var("PATH").ok())?;
+    env::split_paths(&path).map(|d| d.join(command)).find(|p| p.is_file())
+}
+
+/// Checks every command against the policy before handing it on, so no
+/// violating command is ever spawned whatever the plan does.
+pub struct PolicyRunner {
+    pub inner: Arc<dyn CommandRunner>,
+    pub policy: Arc<Policy>,
+}
+
+impl CommandRunner for PolicyRunner {
+    fn run(&self, command: &ExecutableCommand, env: &HashMap<String, String>, on_line: LineSink) -> Result<CommandOutput, Error> {
+        self.policy.check(command, env)?;
+        self.inner.run(command, env, on_line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Policy, PolicyRunner};
+    use crate::deploy::{CommandSet, ExecutableCommand};
+    use crate::runner::{CommandRunner, MockRunner};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn command(c: &str, args: &[&str]) -> ExecutableCommand {
+        ExecutableCommand {
+            idx: 1,
+            command: c.to_owned(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            expect: None,
+            with_items: Vec::new(),
+            user: None,
+            group: None,
+            limits: None,
+            sandbox: None,
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let p = Policy::from_file("./testresource/policy.json").unwrap();
+        let env = HashMap::new();
+        assert!(p.check(&command("sh", &["-c", "true"]), &env).is_ok());
+        assert!(p.check(&command("/bin/sh", &["-c", "true"]), &env).is_ok());
+        let e = p.check(&command("ls", &[]), &env).unwrap_err();
+        assert!(format!("{}", e).starts_with("Policy violation: `"));
+        let e = p.check(&command("sh", &["-c", "rm -rf /"]), &env).unwrap_err();
+        assert!(format!("{}", e).contains("denied pattern"));
+        assert!(p.check(&command("/nonexistent/sh", &[]), &env).is_err());
+
+        let mut env = HashMap::new();
+        env.insert("LD_PRELOAD".to_owned(), "x.so".to_owned());
+        assert!(p.check(&command("sh", &[]), &env).is_err());
+        assert!(p.validate(&CommandSet::new(vec![]), &env).is_err());
+
+        // Set in queenbee's own environment, which commands inherit.
+        let p = Policy { forbid_env: vec!["QUEENBEE_POLICY_TEST_PRELOAD".to_owned()], ..Policy::default() };
+        assert!(p.check(&command("sh", &[]), &HashMap::new()).is_ok());
+        std::env::set_var("QUEENBEE_POLICY_TEST_PRELOAD", "x.so");
+        let e = p.check(&command("sh", &[]), &HashMap::new()).unwrap_err();
+        assert!(format!("{}", e).contains("unset it before running queenbee"), "{}", e);
+        assert!(p.validate(&CommandSet::new(vec![]), &HashMap::new()).is_err());
+        std::env::remove_var("QUEENBEE_POLICY_TEST_PRELOAD");
+
+        let p = Policy::from_file("./testresource/policy.json").unwrap();
+        let templated = CommandSet::new(vec![command("{{ tool }}", &["rm -rf {{ dir }}"])]);
+        assert!(p.validate(&templated, &HashMap::new()).is_ok());
+        assert!(p.validate(&CommandSet::new(vec![command("ls", &[])]), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_policy_runner() {
+        let mock = Arc::new(MockRunner::new());
+        let runner = PolicyRunner { inner: mock.clone(), policy: Arc::new(Policy::from_file("./testresource/policy.json").unwrap()) };
+        let env = HashMap::new();
+        assert!(runner.run(&command("ls", &[]), &env, &|_, _| {}).is_err());
+        assert!(runner.run(&command("sh", &[]), &env, &|_, _| {}).is_ok());
+        assert_eq!(mock.calls().len(), 1);
+    }
+}
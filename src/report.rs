@@ -0,0 +1,199 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+pub mod format;
+pub mod html;
+
+use serde::{Serialize, Deserialize};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use format::ReportFormat;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Failed,
+    Skipped,
+}
+
+/// Everything that happened while running one plan, nested plans included.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunReport {
+    /// Unique for every run, the key of the run in a `History`.
+    pub id: String,
+    pub plan: String,
+    pub status: Status,
+    pub started: SystemTime,
+    pub duration: Duration,
+    /// Tasks in the order they ran, one entry per expanded item.
+    pub tasks: Vec<TaskReport>,
+    /// Why the run stopped, if it failed.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TaskReport {
+    /// The plan the task belongs to, which may be a nested one.
+    pub plan: String,
+    pub task: String,
+    /// Loop variables like `env=prod, item=web` for expanded tasks.
+    pub item: Option<String>,
+    pub status: Status,
+    pub started: SystemTime,
+    pub duration: Duration,
+    pub commands: Vec<CommandReport>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CommandReport {
+    pub idx: usize,
+    pub command: String,
+    pub args: Vec<String>,
+    pub status: Status,
+    pub started: SystemTime,
+    /// `None` if the command could not start or was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+impl RunReport {
+    pub fn new(plan: &str) -> Self {
+        let started = SystemTime::now();
+        RunReport {
+            id: run_id(started),
+            plan: plan.to_owned(),
+            status: Status::Success,
+            started,
+            duration: Duration::default(),
+            tasks: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.status == Status::Success
+    }
+
+    /// Applies `f` to every text which may carry command lines or output,
+    /// used to mask secrets before the report is shown or stored.
+    pub fn redact<F: Fn(&str) -> String>(&mut self, f: F) {
+        self.error = self.error.as_deref().map(&f);
+        for t in &mut self.tasks {
+            t.item = t.item.as_deref().map(&f);
+            t.error = t.error.as_deref().map(&f);
+            for c in &mut t.commands {
+                c.command = f(&c.command);
+                c.args = c.args.iter().map(|a| f(a)).collect();
+                c.stdout = f(&c.stdout);
+                c.stderr = f(&c.stderr);
+                c.error = c.error.as_deref().map(&f);
+            }
+        }
+    }
+
+    /// Fills in the outcome once the plan stopped.
+    pub fn finish(&mut self, result: Result<(), String>) {
+        self.duration = self.started.elapsed().unwrap_or_default();
+        if let Err(e) = result {
+            self.status = Status::Failed;
+            self.error = Some(e);
+        }
+    }
+}
+
+impl TaskReport {
+    pub fn new(plan: &str, task: &str, item: Option<String>) -> Self {
+        TaskReport {
+            plan: plan.to_owned(),
+            task: task.to_owned(),
+            item,
+            status: Status::Success,
+            started: SystemTime::now(),
+            duration: Duration::default(),
+            commands: Vec::new(),
+            error: None,
+        }
+    }
+}
+
+/// The start time in milliseconds followed by bits of the process id and a
+/// counter, so runs started at once by several processes still differ.
+fn run_id(started: SystemTime) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let ms = started.duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}{:04x}", ms, (process::id() as usize ^ n.wrapping_mul(0x9e37)) & 0xffff)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandReport, RunReport, Status, TaskReport};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_finish() {
+        let mut r = RunReport::new("Plan1");
+        r.finish(Ok(()));
+        assert!(r.success());
+        let mut r = RunReport::new("Plan1");
+        r.finish(Err("boom".to_owned()));
+        assert_eq!(r.status, Status::Failed);
+        assert_eq!(r.error.as_deref(), Some("boom"));
+    }
+
+    #[test]
+    fn test_redact() {
+        let mut r = RunReport::new("Plan1");
+        let mut t = TaskReport::new("Plan1", "Task1", Some("pw=hunter2".to_owned()));
+        t.commands.push(CommandReport {
+            idx: 0,
+            command: "/opt/hunter2/mysql".to_owned(),
+            args: vec!["-phunter2".to_owned()],
+            status: Status::Failed,
+            started: r.started,
+            exit_code: Some(1),
+            stdout: "hunter2".to_owned(),
+            stderr: String::new(),
+            duration: Default::default(),
+            error: Some("hunter2 refused".to_owned()),
+        });
+        r.tasks.push(t);
+        r.redact(|s| s.replace("hunter2", "***"));
+        let json = serde_json::to_string(&r).unwrap();
+        assert!(!json.contains("hunter2"), "{}", json);
+        assert_eq!(r.tasks[0].item.as_deref(), Some("pw=***"));
+        assert_eq!(r.tasks[0].commands[0].command, "/opt/***/mysql");
+    }
+
+    #[test]
+    fn test_run_id() {
+        let ids: HashSet<String> = (0..100).map(|_| RunReport::new("p").id).collect();
+        assert_eq!(ids.len(), 100);
+    }
+}
@@ -0,0 +1,289 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use super::{RunReport, Status, TaskReport};
+use crate::Error;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// How many trailing lines of stderr a failure shows.
+pub const STDERR_TAIL_LINES: usize = 20;
+
+/// File formats a `RunReport` can be written in.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReportFormat {
+    /// JUnit XML, one test suite per plan and one test case per task item.
+    Junit,
+    /// A summary table per plan followed by the failures.
+    Markdown,
+    /// A single page with a timeline and the output of every command.
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "junit" => Ok(ReportFormat::Junit),
+            "markdown" | "md" => Ok(ReportFormat::Markdown),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(Error::Failed(format!("Unknown report format `{}`, expect `junit`, `markdown` or `html`!", s))),
+        }
+    }
+}
+
+impl RunReport {
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Junit => self.to_junit(),
+            ReportFormat::Markdown => self.to_markdown(),
+            ReportFormat::Html => self.to_html(),
+        }
+    }
+
+    /// Tasks grouped by the plan they belong to, plans in the order they
+    /// started.
+    fn by_plan(&self) -> Vec<(&str, Vec<&TaskReport>)> {
+        let mut v: Vec<(&str, Vec<&TaskReport>)> = Vec::new();
+        for t in &self.tasks {
+            match v.iter_mut().find(|(p, _)| *p == t.plan) {
+                Some((_, tasks)) => tasks.push(t),
+                None => v.push((&t.plan, vec![t])),
+            }
+        }
+        if v.is_empty() {
+            v.push((&self.plan, Vec::new()));
+        }
+        v
+    }
+
+    /// An error which stopped the run without any task failing, like a
+    /// template error in a plan.
+    fn plan_error(&self) -> Option<&str> {
+        if self.tasks.iter().any(|t| t.status == Status::Failed) {
+            return None;
+        }
+        self.error.as_deref()
+    }
+
+    pub fn to_junit(&self) -> String {
+        let mut s = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let failures = self.tasks.iter().filter(|t| t.status == Status::Failed).count() + self.plan_error().map_or(0, |_| 1);
+        let _ = writeln!(s, "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+                         xml_escape(&self.plan), self.tasks.len(), failures, self.duration.as_secs_f64());
+        for (plan, tasks) in self.by_plan() {
+            let mut failed = tasks.iter().filter(|t| t.status == Status::Failed).count();
+            let skipped = tasks.iter().filter(|t| t.status == Status::Skipped).count();
+            let plan_error = if plan == self.plan { self.plan_error() } else { None };
+            if plan_error.is_some() {
+                failed += 1;
+            }
+            let time: f64 = tasks.iter().map(|t| t.duration.as_secs_f64()).sum();
+            let _ = writeln!(s, "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">",
+                             xml_escape(plan), tasks.len() + plan_error.map_or(0, |_| 1), failed, skipped, time);
+            for t in tasks {
+                let _ = write!(s, "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                               xml_escape(plan), xml_escape(&case_name(t)), t.duration.as_secs_f64());
+                match t.status {
+                    Status::Success => s.push_str("/>\n"),
+                    Status::Skipped => s.push_str(">\n      <skipped/>\n    </testcase>\n"),
+                    Status::Failed => {
+                        let _ = writeln!(s, ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                                         xml_escape(t.error.as_deref().and_then(|e| e.lines().next()).unwrap_or("failed")), xml_escape(&failure_detail(t)));
+                    },
+                }
+            }
+            if let Some(e) = plan_error {
+                let _ = writeln!(s, "    <testcase classname=\"{0}\" name=\"{0}\" time=\"0.000\">\n      <failure message=\"{1}\">{1}</failure>\n    </testcase>",
+                                 xml_escape(plan), xml_escape(e));
+            }
+            s.push_str("  </testsuite>\n");
+        }
+        s.push_str("</testsuites>\n");
+        s
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(s, "# Plan `{}`: {}\n", self.plan, status_text(self.status));
+        let _ = writeln!(s, "Duration: {:.3}s\n", self.duration.as_secs_f64());
+        for (plan, tasks) in self.by_plan() {
+            if tasks.is_empty() {
+                continue;
+            }
+            let _ = writeln!(s, "## {}\n", plan);
+            s.push_str("| Task | Status | Duration | Exit codes |\n|---|---|---|---|\n");
+            for t in &tasks {
+                let codes: Vec<String> = t.commands.iter()
+                    .map(|c| c.exit_code.map_or("-".to_owned(), |e| e.to_string()))
+                    .collect();
+                let _ = writeln!(s, "| {} | {} | {:.3}s | {} |",
+                                 md_escape(&case_name(t)), status_text(t.status), t.duration.as_secs_f64(), codes.join(" "));
+            }
+            s.push('\n');
+        }
+        let failed: Vec<&TaskReport> = self.tasks.iter().filter(|t| t.status == Status::Failed).collect();
+        if !failed.is_empty() || self.error.is_some() {
+            s.push_str("## Failures\n\n");
+        }
+        for t in failed {
+            let _ = writeln!(s, "### {} / {}\n", t.plan, case_name(t));
+            let _ = writeln!(s, "```\n{}\n```\n", failure_detail(t).trim_end());
+        }
+        if let Some(e) = self.plan_error() {
+            let _ = writeln!(s, "```\n{}\n```\n", e);
+        }
+        s
+    }
+}
+
+pub(super) fn case_name(t: &TaskReport) -> String {
+    match &t.item {
+        Some(i) => format!("{} [{}]", t.task, i),
+        None => t.task.clone(),
+    }
+}
+
+pub(super) fn status_text(s: Status) -> &'static str {
+    match s {
+        Status::Success => "success",
+        Status::Failed => "failed",
+        Status::Skipped => "skipped",
+    }
+}
+
+/// The error, the failing command with its exit code and the end of its
+/// stderr.
+fn failure_detail(t: &TaskReport) -> String {
+    let mut s = String::new();
+    if let Some(e) = &t.error {
+        let _ = writeln!(s, "{}", e);
+    }
+    if let Some(c) = t.commands.iter().rev().find(|c| c.status == Status::Failed) {
+        let _ = writeln!(s, "command: {} {:?}", c.command, c.args);
+        match c.exit_code {
+            Some(code) => { let _ = writeln!(s, "exit code: {}", code); },
+            None => s.push_str("exit code: none\n"),
+        }
+        let lines: Vec<&str> = c.stderr.lines().collect();
+        if !lines.is_empty() {
+            let from = lines.len().saturating_sub(STDERR_TAIL_LINES);
+            let _ = writeln!(s, "stderr (last {} lines):", lines.len() - from);
+            for l in &lines[from..] {
+                let _ = writeln!(s, "{}", l);
+            }
+        }
+    }
+    s
+}
+
+pub(super) fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Not allowed in XML 1.0 at all.
+            c if (c as u32) < 0x20 && c != '\n' && c != '\t' && c != '\r' => {},
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn md_escape(s: &str) -> String {
+    s.replace('|', "\\|")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReportFormat, STDERR_TAIL_LINES};
+    use crate::report::{CommandReport, RunReport, Status, TaskReport};
+    use std::time::{Duration, SystemTime};
+
+    fn report() -> RunReport {
+        let mut r = RunReport::new("Release");
+        let mut ok = TaskReport::new("Build", "compile", None);
+        ok.duration = Duration::from_millis(1250);
+        let mut failed = TaskReport::new("Release", "deploy", Some("env=a<b".to_owned()));
+        failed.status = Status::Failed;
+        failed.error = Some("Expectation failed".to_owned());
+        let stderr: Vec<String> = (0..30).map(|i| format!("line {}", i)).collect();
+        failed.commands.push(CommandReport {
+            idx: 1,
+            command: "ssh".to_owned(),
+            args: vec!["host".to_owned()],
+            status: Status::Failed,
+            started: SystemTime::now(),
+            exit_code: Some(255),
+            stdout: String::new(),
+            stderr: stderr.join("\n"),
+            duration: Duration::default(),
+            error: None,
+        });
+        let mut skipped = TaskReport::new("Release", "deploy", Some("env=c".to_owned()));
+        skipped.status = Status::Skipped;
+        r.tasks = vec![ok, failed, skipped];
+        r.finish(Err("Task `deploy` failed".to_owned()));
+        r
+    }
+
+    #[test]
+    fn test_junit() {
+        let xml = report().render(ReportFormat::Junit);
+        assert!(xml.contains("<testsuites name=\"Release\" tests=\"3\" failures=\"1\""));
+        assert!(xml.contains("<testsuite name=\"Build\" tests=\"1\" failures=\"0\" skipped=\"0\" time=\"1.250\">"));
+        assert!(xml.contains("<testcase classname=\"Release\" name=\"deploy [env=a&lt;b]\""));
+        assert!(xml.contains("<failure message=\"Expectation failed\">"));
+        assert!(xml.contains("exit code: 255"));
+        assert!(xml.contains(&format!("stderr (last {} lines):\nline 10\n", STDERR_TAIL_LINES)));
+        assert!(!xml.contains("line 9\n"));
+        assert!(xml.contains("name=\"deploy [env=c]\" time=\"0.000\">\n      <skipped/>"));
+    }
+
+    #[test]
+    fn test_plan_error() {
+        let mut r = RunReport::new("Broken");
+        r.finish(Err("Unknown variable `x`".to_owned()));
+        let xml = r.to_junit();
+        assert!(xml.contains("<testsuite name=\"Broken\" tests=\"1\" failures=\"1\""));
+        assert!(xml.contains("<failure message=\"Unknown variable `x`\">"));
+        assert!(r.to_markdown().contains("## Failures\n\n```\nUnknown variable `x`\n```"));
+    }
+
+    #[test]
+    fn test_markdown() {
+        let md = report().render(ReportFormat::Markdown);
+        assert!(md.starts_with("# Plan `Release`: failed\n"));
+        assert!(md.contains("## Build\n"));
+        assert!(md.contains("| compile | success | 1.250s |  |"));
+        assert!(md.contains("| deploy [env=a<b] | failed | 0.000s | 255 |"));
+        assert!(md.contains("| deploy [env=c] | skipped |"));
+        assert!(md.contains("### Release / deploy [env=a<b]\n\n```\nExpectation failed\ncommand: ssh [\"host\"]\nexit code: 255\n"));
+        assert_eq!("md".parse::<ReportFormat>().unwrap(), ReportFormat::Markdown);
+        assert!("pdf".parse::<ReportFormat>().is_err());
+    }
+}
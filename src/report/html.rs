@@ -0,0 +1,168 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use super::{RunReport, Status, CommandReport};
+use super::format::{case_name, status_text, xml_escape};
+use chrono::{DateTime, Local};
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #222; }
+code, pre { font-family: monospace; }
+pre { background: #f6f6f6; padding: .5em; overflow-x: auto; margin: .3em 0; }
+pre.stderr { background: #fff0f0; }
+.status { padding: 0 .4em; border-radius: 3px; color: #fff; font-size: .9em; }
+.success { background: #2e7d32; }
+.failed { background: #c62828; }
+.skipped { background: #9e9e9e; }
+.error { color: #c62828; white-space: pre-wrap; }
+.row { display: flex; align-items: center; margin: 2px 0; }
+.label { width: 30%; overflow: hidden; text-overflow: ellipsis; white-space: nowrap; font-size: .9em; }
+.track { position: relative; flex: 1; height: 1.1em; background: #eee; }
+.bar { position: absolute; top: 0; bottom: 0; min-width: 2px; }
+details { margin-left: 1em; }
+summary { cursor: pointer; }
+";
+
+impl RunReport {
+    /// A single HTML page without outside resources: a timeline of the
+    /// tasks, where parallel items show up side by side, followed by every
+    /// command with its output folded away.
+    pub fn to_html(&self) -> String {
+        let mut s = String::new();
+        let title = format!("Plan {}", self.plan);
+        let _ = writeln!(s, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>",
+                         xml_escape(&title), STYLE);
+        let _ = writeln!(s, "<h1>Plan <code>{}</code> {}</h1>", xml_escape(&self.plan), badge(self.status));
+        let _ = writeln!(s, "<p>Run <code>{}</code>, started {}, took {}</p>",
+                         xml_escape(&self.id), local_time(self.started), seconds(self.duration));
+        if let Some(e) = &self.error {
+            let _ = writeln!(s, "<p class=\"error\">{}</p>", xml_escape(e));
+        }
+
+        let total = self.tasks.iter()
+            .map(|t| self.offset(t.started) + t.duration)
+            .fold(self.duration, Duration::max)
+            .max(Duration::from_millis(1))
+            .as_secs_f64();
+        s.push_str("<h2>Timeline</h2>\n<div class=\"timeline\">\n");
+        for t in &self.tasks {
+            let left = self.offset(t.started).as_secs_f64() / total * 100.0;
+            let width = t.duration.as_secs_f64() / total * 100.0;
+            let label = format!("{} / {}", t.plan, case_name(t));
+            let _ = writeln!(s, "<div class=\"row\"><div class=\"label\" title=\"{0}\">{0}</div><div class=\"track\"><div class=\"bar {1}\" style=\"left: {2:.2}%; width: {3:.2}%\" title=\"+{4} for {5}\"></div></div></div>",
+                             xml_escape(&label), status_text(t.status), left, width,
+                             seconds(self.offset(t.started)), seconds(t.duration));
+        }
+        s.push_str("</div>\n<h2>Tasks</h2>\n");
+        for t in &self.tasks {
+            let _ = writeln!(s, "<h3>{} / {} {} <small>{}</small></h3>",
+                             xml_escape(&t.plan), xml_escape(&case_name(t)), badge(t.status), seconds(t.duration));
+            if let Some(e) = &t.error {
+                let _ = writeln!(s, "<p class=\"error\">{}</p>", xml_escape(e));
+            }
+            for c in &t.commands {
+                command_html(&mut s, c);
+            }
+        }
+        s.push_str("</body>\n</html>\n");
+        s
+    }
+
+    /// How long after the start of the run `t` was.
+    fn offset(&self, t: SystemTime) -> Duration {
+        t.duration_since(self.started).unwrap_or_default()
+    }
+}
+
+fn command_html(s: &mut String, c: &CommandReport) {
+    let exit = c.exit_code.map_or("none".to_owned(), |e| e.to_string());
+    let open = if c.status == Status::Failed { " open" } else { "" };
+    let _ = writeln!(s, "<details{}><summary><code>{} {}</code> {} exit code {}, {}</summary>",
+                     open, xml_escape(&c.command), xml_escape(&format!("{:?}", c.args)), badge(c.status), exit, seconds(c.duration));
+    if let Some(e) = &c.error {
+        let _ = writeln!(s, "<p class=\"error\">{}</p>", xml_escape(e));
+    }
+    if !c.stdout.is_empty() {
+        let _ = writeln!(s, "<pre>{}</pre>", xml_escape(&c.stdout));
+    }
+    if !c.stderr.is_empty() {
+        let _ = writeln!(s, "<pre class=\"stderr\">{}</pre>", xml_escape(&c.stderr));
+    }
+    s.push_str("</details>\n");
+}
+
+fn badge(status: Status) -> String {
+    format!("<span class=\"status {0}\">{0}</span>", status_text(status))
+}
+
+fn seconds(d: Duration) -> String {
+    format!("{:.3}s", d.as_secs_f64())
+}
+
+fn local_time(t: SystemTime) -> String {
+    DateTime::<Local>::from(t).format("%Y-%m-%d %H:%M:%S %z").to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::report::{CommandReport, ReportFormat, RunReport, Status, TaskReport};
+    use std::time::Duration;
+
+    #[test]
+    fn test_html() {
+        let mut r = RunReport::new("Release");
+        let mut web = TaskReport::new("Release", "deploy", Some("item=web".to_owned()));
+        web.started = r.started + Duration::from_secs(1);
+        web.duration = Duration::from_secs(2);
+        web.commands.push(CommandReport {
+            idx: 1,
+            command: "echo".to_owned(),
+            args: vec!["<hi>".to_owned()],
+            status: Status::Success,
+            started: web.started,
+            exit_code: Some(0),
+            stdout: "<hi>\n".to_owned(),
+            stderr: String::new(),
+            duration: Duration::from_secs(2),
+            error: None,
+        });
+        let mut db = TaskReport::new("Release", "deploy", Some("item=db".to_owned()));
+        db.started = web.started;
+        db.duration = Duration::from_secs(3);
+        db.status = Status::Failed;
+        r.tasks = vec![web, db];
+        r.duration = Duration::from_secs(4);
+        r.status = Status::Failed;
+
+        let html = r.render(ReportFormat::Html);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(!html.contains("<script") && !html.contains("<link"));
+        assert!(html.contains(&format!("Run <code>{}</code>", r.id)));
+        assert!(html.contains("title=\"Release / deploy [item=web]\""));
+        assert!(html.contains("class=\"bar success\" style=\"left: 25.00%; width: 50.00%\""));
+        assert!(html.contains("class=\"bar failed\" style=\"left: 25.00%; width: 75.00%\""));
+        assert!(html.contains("<pre>&lt;hi&gt;\n</pre>"));
+        assert!(html.contains("<details><summary><code>echo [&quot;&lt;hi&gt;&quot;]</code>"));
+    }
+}
@@ -0,0 +1,209 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use crate::identity::user_name;
+use crate::integrity::sha256;
+use chrono::{DateTime, Local};
+use serde::{Serialize, Deserialize};
+use std::fs::{self, DirBuilder, File, OpenOptions, Permissions, TryLockError};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The lock every run takes when the config sets `global_lock`.
+pub const GLOBAL_LOCK: &str = "queenbee-global";
+/// How often a waiting run tries the lock again.
+const RETRY: Duration = Duration::from_millis(100);
+
+/// Exclusive locks keeping two runs from deploying the same thing at
+/// once, one `flock`ed file per lock name in `dir`. A lock is released
+/// when its holder ends, even if it crashes. `dir` and the lock files are
+/// made group-writable, so the users of one group share them.
+#[derive(Debug, Clone)]
+pub struct Locks {
+    dir: PathBuf,
+    scope: String,
+}
+
+/// Who holds a lock, written into the lock file for the runs waiting.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub user: String,
+    pub plan: String,
+    pub started_ms: u64,
+}
+
+/// A held lock, released when dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    name: String,
+    _file: File,
+}
+
+impl RunLock {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Locks {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Locks { dir: dir.as_ref().to_path_buf(), scope: String::new() }
+    }
+
+    /// Keeps these locks apart from the ones of other configs sharing
+    /// `dir`, all but `GLOBAL_LOCK` which stays shared.
+    pub fn with_scope(mut self, scope: &str) -> Self {
+        self.scope = scope.to_owned();
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Takes the lock `name` for a run of `plan`, trying for `timeout`
+    /// before failing with who holds it.
+    pub fn acquire(&self, name: &str, plan: &str, timeout: Duration) -> Result<RunLock, Error> {
+        self.create_dir()?;
+        let path = self.path(name);
+        let mut file = open_shared(&path)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => thread::sleep(RETRY),
+                Err(TryLockError::WouldBlock) => return Err(Error::Failed(held(name, &path, timeout))),
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+        // SAFETY: getuid cannot fail.
+        let holder = LockHolder {
+            pid: std::process::id(),
+            user: user_name(unsafe { libc::getuid() }),
+            plan: plan.to_owned(),
+            started_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        };
+        file.set_len(0)?;
+        file.write_all(serde_json::to_string(&holder)?.as_bytes())?;
+        Ok(RunLock { name: name.to_owned(), _file: file })
+    }
+
+    /// The file of lock `name`, the readable part of the name followed by
+    /// a hash of the scope and the whole name, so names differing only in
+    /// replaced characters do not share a file.
+    fn path(&self, name: &str) -> PathBuf {
+        let key = if name == GLOBAL_LOCK { name.to_owned() } else { format!("{}\0{}", self.scope, name) };
+        self.dir.join(format!("{}-{}.lock", file_name(name), &sha256(key.as_bytes())[..16]))
+    }
+
+    fn create_dir(&self) -> io::Result<()> {
+        if self.dir.is_dir() {
+            return Ok(());
+        }
+        if let Some(parent) = self.dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match DirBuilder::new().mode(0o2775).create(&self.dir) {
+            // Not left to the umask.
+            Ok(()) => fs::set_permissions(&self.dir, Permissions::from_mode(0o2775)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Opens the lock file at `path`, creating it group-writable if missing.
+fn open_shared(path: &Path) -> io::Result<File> {
+    match OpenOptions::new().read(true).write(true).create_new(true).mode(0o664).open(path) {
+        Ok(f) => {
+            f.set_permissions(Permissions::from_mode(0o664))?;
+            Ok(f)
+        },
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => OpenOptions::new().read(true).write(true).open(path),
+        Err(e) => Err(e),
+    }
+}
+
+fn held(name: &str, path: &Path, timeout: Duration) -> String {
+    let waited = if timeout.is_zero() { String::new() } else { format!(" after waiting {}s", timeout.as_secs_f64()) };
+    match fs::read_to_string(path).ok().and_then(|s| serde_json::from_str::<LockHolder>(&s).ok()) {
+        Some(h) => {
+            let since = DateTime::<Local>::from(UNIX_EPOCH + Duration::from_millis(h.started_ms));
+            format!("Lock `{}` is held by pid {} ({}) running plan `{}` since {}{}!",
+                name, h.pid, h.user, h.plan, since.format("%Y-%m-%d %H:%M:%S"), waited)
+        },
+        None => format!("Lock `{}` is held by another run{}!", name, waited),
+    }
+}
+
+/// `name` with anything but letters, digits, `-`, `_` and `.` replaced.
+fn file_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LockHolder, Locks, GLOBAL_LOCK};
+    use crate::testutil::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_locks() {
+        let tmp = TempDir::new("locks");
+        let dir = tmp.join("locks");
+        let locks = Locks::new(&dir);
+        let held = locks.acquire("web/prod", "release", Duration::ZERO).unwrap();
+        assert_eq!(held.name(), "web/prod");
+        let holder: LockHolder = serde_json::from_str(&std::fs::read_to_string(locks.path("web/prod")).unwrap()).unwrap();
+        assert_eq!((holder.pid, holder.plan.as_str()), (std::process::id(), "release"));
+        assert_eq!(std::fs::metadata(&dir).unwrap().permissions().mode() & 0o7777, 0o2775);
+        assert_eq!(std::fs::metadata(locks.path("web/prod")).unwrap().permissions().mode() & 0o777, 0o664);
+
+        // flock locks belong to the open file, so a second open conflicts
+        // even within one process.
+        let e = locks.acquire("web/prod", "hotfix", Duration::ZERO).unwrap_err().to_string();
+        assert!(e.starts_with(&format!("Lock `web/prod` is held by pid {} (", std::process::id())), "{}", e);
+        assert!(e.contains("running plan `release` since "), "{}", e);
+        let started = Instant::now();
+        let e = locks.acquire("web/prod", "hotfix", Duration::from_millis(300)).unwrap_err().to_string();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(e.ends_with(" after waiting 0.3s!"), "{}", e);
+        assert!(locks.acquire("db", "hotfix", Duration::ZERO).is_ok());
+        // Same file name once sanitized, still another lock.
+        assert!(locks.acquire("web_prod", "hotfix", Duration::ZERO).is_ok());
+        // Another config sharing the directory only shares the global lock.
+        let other = Locks::new(&dir).with_scope("/etc/other/config.json");
+        assert!(other.acquire("web/prod", "hotfix", Duration::ZERO).is_ok());
+        let global = locks.acquire(GLOBAL_LOCK, "release", Duration::ZERO).unwrap();
+        assert!(other.acquire(GLOBAL_LOCK, "hotfix", Duration::ZERO).is_err());
+        drop(global);
+
+        drop(held);
+        assert!(locks.acquire("web/prod", "hotfix", Duration::ZERO).is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use crate::deploy::ExecutableCommand;
+use crate::event::Observer;
+use crate::report::RunReport;
+use crate::secret::Secrets;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::thread;
+
+/// Which output of a command a line came from.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Called with every line a command prints, without the line break, while
+/// it is still running.
+pub type LineSink<'a> = &'a (dyn Fn(Stream, &str) + Sync);
+
+/// What a finished command left behind.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CommandOutput {
+    /// `None` if the command was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(stdout: &str) -> Self {
+        CommandOutput {
+            exit_code: Some(0),
+            stdout: stdout.to_owned(),
+            stderr: String::new(),
+        }
+    }
+
+    pub fn failure(exit_code: i32, stderr: &str) -> Self {
+        CommandOutput {
+            exit_code: Some(exit_code),
+            stdout: String::new(),
+            stderr: stderr.to_owned(),
+        }
+    }
+}
+
+/// Runs one concrete command, already expanded and templated. Tasks only
+/// execute commands through this, so it can be swapped for tests or other
+/// backends. Output is handed to `on_line` as it comes and returned whole
+/// in the `CommandOutput`.
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, command: &ExecutableCommand, env: &HashMap<String, String>, on_line: LineSink) -> Result<CommandOutput, Error>;
+}
+
+/// Runs commands as local child processes, the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalRunner;
+
+impl CommandRunner for LocalRunner {
+    fn run(&self, command: &ExecutableCommand, env: &HashMap<String, String>, on_line: LineSink) -> Result<CommandOutput, Error> {
+        let mut c = command.as_command();
+        // Limits and sandbox first, a negative nice value and the mounts
+        // need the privileges the identity may drop.
+        if let Some(l) = &command.limits {
+            l.apply(&mut c)?;
+        }
+        if let Some(s) = &command.sandbox {
+            s.apply(&mut c)?;
+        }
+        if let Some(id) = command.identity()? {
+            id.apply(&mut c)?;
+        }
+        // After the identity, an explicit `HOME`, `USER` or `LOGNAME` wins.
+        let mut child = c
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let (stdout, stderr) = thread::scope(|s| {
+            let out = s.spawn(|| read_lines(stdout, Stream::Stdout, on_line));
+            let err = read_lines(stderr, Stream::Stderr, on_line);
+            (out.join().unwrap_or_default(), err)
+        });
+        let status = child.wait()?;
+        Ok(CommandOutput {
+            exit_code: status.code(),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// Reads `r` to the end, passing on each line and returning all of it.
+fn read_lines<R: Read>(r: R, stream: Stream, on_line: LineSink) -> String {
+    let mut r = BufReader::new(r);
+    let mut all = String::new();
+    let mut buf: Vec<u8> = Vec::new();
+    loop {
+        buf.clear();
+        match r.read_until(b'\n', &mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                on_line(stream, line.trim_end_matches(&['\n', '\r'][..]));
+                all.push_str(&line);
+            },
+        }
+    }
+    all
+}
+
+/// Records every command instead of running it and answers with scripted
+/// outputs, commands without a matching answer succeed with no output.
+///
+/// ```
+/// use queenbee::runner::{CommandOutput, MockRunner};
+///
+/// let runner = MockRunner::new()
+///     .respond("cargo -V", CommandOutput::success("cargo 1.0.0\n"))
+///     .respond_once("rustup", CommandOutput::failure(1, "offline"));
+/// assert!(runner.calls().is_empty());
+/// ```
+#[derive(Debug, Default)]
+pub struct MockRunner {
+    script: Mutex<Vec<(String, CommandOutput, bool)>>,
+    calls: Mutex<Vec<ExecutableCommand>>,
+}
+
+impl MockRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers every command whose line (`command arg1 arg2...`) starts
+    /// with `prefix`. Earlier answers win over later ones.
+    pub fn respond(self, prefix: &str, output: CommandOutput) -> Self {
+        self.script.lock().unwrap().push((prefix.to_owned(), output, false));
+        self
+    }
+
+    /// Like `respond`, but only for the first matching command.
+    pub fn respond_once(self, prefix: &str, output: CommandOutput) -> Self {
+        self.script.lock().unwrap().push((prefix.to_owned(), output, true));
+        self
+    }
+
+    /// Commands run so far, in order.
+    pub fn calls(&self) -> Vec<ExecutableCommand> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for MockRunner {
+    fn run(&self, command: &ExecutableCommand, _env: &HashMap<String, String>, on_line: LineSink) -> Result<CommandOutput, Error> {
+        self.calls.lock().unwrap().push(command.clone());
+        let mut line = command.command.clone();
+        for a in &command.args {
+            line.push(' ');
+            line.push_str(a);
+        }
+        let output = {
+            let mut script = self.script.lock().unwrap();
+            match script.iter().position(|(prefix, _, _)| line.starts_with(prefix.as_str())) {
+                Some(i) if script[i].2 => script.remove(i).1,
+                Some(i) => script[i].1.clone(),
+                None => CommandOutput::success(""),
+            }
+        };
+        for l in output.stdout.lines() {
+            on_line(Stream::Stdout, l);
+        }
+        for l in output.stderr.lines() {
+            on_line(Stream::Stderr, l);
+        }
+        Ok(output)
+    }
+}
+
+/// What a running plan needs besides its own definition.
+pub struct RunContext<'a> {
+    pub runner: &'a dyn CommandRunner,
+    pub observer: &'a dyn Observer,
+    /// Exported to every command next to the config's `env`.
+    pub secrets: &'a Secrets,
+    pub report: &'a mut RunReport,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandOutput, CommandRunner, LocalRunner, MockRunner, Stream};
+    use crate::deploy::ExecutableCommand;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn command(c: &str, args: &[&str]) -> ExecutableCommand {
+        ExecutableCommand {
+            idx: 1,
+            command: c.to_owned(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            expect: None,
+            with_items: Vec::new(),
+            user: None,
+            group: None,
+            limits: None,
+            sandbox: None,
+        }
+    }
+
+    #[test]
+    fn test_local_runner() {
+        let mut env = HashMap::new();
+        env.insert("QUEENBEE_TEST".to_owned(), "bee".to_owned());
+        let lines = Mutex::new(Vec::new());
+        let on_line = |s: Stream, l: &str| lines.lock().unwrap().push((s, l.to_owned()));
+        let out = LocalRunner.run(&command("sh", &["-c", "echo $QUEENBEE_TEST; echo oops >&2; exit 4"]), &env, &on_line).unwrap();
+        assert_eq!(out.exit_code, Some(4));
+        assert_eq!(out.stdout, "bee\n");
+        assert_eq!(out.stderr, "oops\n");
+        let mut lines = lines.into_inner().unwrap();
+        lines.sort_by_key(|(s, _)| *s == Stream::Stderr);
+        assert_eq!(lines, vec![(Stream::Stdout, "bee".to_owned()), (Stream::Stderr, "oops".to_owned())]);
+        assert!(LocalRunner.run(&command("/nonexistent/queenbee", &[]), &env, &|_, _| {}).is_err());
+    }
+
+    #[test]
+    fn test_mock_runner() {
+        let runner = MockRunner::new()
+            .respond_once("cargo build", CommandOutput::failure(101, "error"))
+            .respond("cargo", CommandOutput::success("ok"));
+        let env = HashMap::new();
+        let none = |_: Stream, _: &str| {};
+        assert_eq!(runner.run(&command("cargo", &["build"]), &env, &none).unwrap().exit_code, Some(101));
+        assert_eq!(runner.run(&command("cargo", &["build"]), &env, &none).unwrap().stdout, "ok");
+        assert_eq!(runner.run(&command("ls", &[]), &env, &none).unwrap(), CommandOutput::success(""));
+        let calls: Vec<String> = runner.calls().iter().map(|c| c.command.clone()).collect();
+        assert_eq!(calls, vec!["cargo", "cargo", "ls"]);
+    }
+}
@@ -0,0 +1,297 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use serde::{Serialize, Deserialize};
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// Runs a command in its own mount, pid and network namespaces, for
+/// untrusted or experimental commands. The whole filesystem is read-only
+/// but for `scratch` and `writable`. Without root, an unprivileged user
+/// namespace is created first, which the kernel has to allow.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct Sandbox {
+    /// Keeps the network of the host, the command only has a loopback
+    /// device otherwise.
+    #[serde(default)]
+    pub network: bool,
+    /// Where an empty writable directory is mounted, gone with the command.
+    #[serde(default = "default_scratch")]
+    pub scratch: String,
+    /// Directories of the host the command may still write to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub writable: Vec<String>,
+}
+
+fn default_scratch() -> String {
+    "/tmp".to_owned()
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox { network: false, scratch: default_scratch(), writable: Vec::new() }
+    }
+}
+
+// Not in every libc release, values from the kernel's uapi headers.
+#[cfg(target_os = "linux")]
+const AT_RECURSIVE: libc::c_uint = 0x8000;
+#[cfg(target_os = "linux")]
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+#[cfg(target_os = "linux")]
+const SIOCGIFFLAGS: libc::c_ulong = 0x8913;
+#[cfg(target_os = "linux")]
+const SIOCSIFFLAGS: libc::c_ulong = 0x8914;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct IfReq {
+    name: [u8; 16],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+#[cfg(target_os = "linux")]
+/// Everything the child needs, prepared before fork as nothing may be
+/// allocated after.
+struct Prepared {
+    flags: libc::c_int,
+    /// `/proc/self/uid_map` and `gid_map` contents when a user namespace
+    /// is needed.
+    maps: Option<(Vec<u8>, Vec<u8>)>,
+    root: CString,
+    scratch: CString,
+    writable: Vec<CString>,
+    network: bool,
+    tmpfs: CString,
+    proc_: CString,
+    proc_dir: CString,
+    uid_map: CString,
+    gid_map: CString,
+    setgroups: CString,
+}
+
+impl Sandbox {
+    pub fn validate(&self) -> Result<(), Error> {
+        for p in std::iter::once(&self.scratch).chain(&self.writable) {
+            if !Path::new(p).is_absolute() {
+                return Err(Error::Failed(format!("Sandbox path `{}` is not absolute!", p)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the child of `c` enter the sandbox right before exec. The
+    /// child forks once more to be the first process of the new pid
+    /// namespace, the command's exit code is passed on and a signal `n`
+    /// killing it gives exit code 128 + n.
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self, c: &mut Command) -> Result<(), Error> {
+        use std::os::unix::process::CommandExt;
+        let cstr = |s: &str| CString::new(s).map_err(|_| Error::Failed(format!("Invalid sandbox path `{}`!", s)));
+        // SAFETY: these calls cannot fail.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+        if !self.network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        let p = Prepared {
+            flags,
+            maps: if uid == 0 { None } else { Some((format!("{0} {0} 1", uid).into_bytes(), format!("{0} {0} 1", gid).into_bytes())) },
+            root: cstr("/")?,
+            scratch: cstr(&self.scratch)?,
+            writable: self.writable.iter().map(|w| cstr(w)).collect::<Result<_, _>>()?,
+            network: self.network,
+            tmpfs: cstr("tmpfs")?,
+            proc_: cstr("proc")?,
+            proc_dir: cstr("/proc")?,
+            uid_map: cstr("/proc/self/uid_map")?,
+            gid_map: cstr("/proc/self/gid_map")?,
+            setgroups: cstr("/proc/self/setgroups")?,
+        };
+        // SAFETY: only async-signal-safe calls on the prepared values.
+        unsafe {
+            c.pre_exec(move || enter(&p));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self, _c: &mut Command) -> Result<(), Error> {
+        Err(Error::from("Sandboxes are unsupported on this platform!"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Runs in the forked child before exec.
+unsafe fn enter(p: &Prepared) -> io::Result<()> {
+    fn check(r: libc::c_int) -> io::Result<()> {
+        if r < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    }
+    if let Some((uid_map, gid_map)) = &p.maps {
+        check(libc::unshare(libc::CLONE_NEWUSER))?;
+        write_file(&p.setgroups, b"deny")?;
+        write_file(&p.uid_map, uid_map)?;
+        write_file(&p.gid_map, gid_map)?;
+    }
+    check(libc::unshare(p.flags))?;
+
+    // Only children enter the new pid namespace, so this process stays
+    // behind to pass on how the command ended.
+    let pid = libc::fork();
+    if pid < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if pid > 0 {
+        // Closing the rest lets `spawn` see the exec of the command.
+        if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) != 0 {
+            for fd in 3..1024 {
+                libc::close(fd);
+            }
+        }
+        let mut status = 0;
+        while libc::waitpid(pid, &mut status, 0) < 0 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                libc::_exit(127);
+            }
+        }
+        if libc::WIFEXITED(status) {
+            libc::_exit(libc::WEXITSTATUS(status));
+        }
+        libc::_exit(128 + libc::WTERMSIG(status));
+    }
+    check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+    let null = std::ptr::null();
+    check(libc::mount(null, p.root.as_ptr(), null, libc::MS_REC | libc::MS_PRIVATE, null as *const libc::c_void))?;
+    for w in &p.writable {
+        check(libc::mount(w.as_ptr(), w.as_ptr(), null, libc::MS_BIND | libc::MS_REC, null as *const libc::c_void))?;
+    }
+    set_read_only(&p.root, true)?;
+    for w in &p.writable {
+        set_read_only(w, false)?;
+    }
+    check(libc::mount(p.tmpfs.as_ptr(), p.scratch.as_ptr(), p.tmpfs.as_ptr(), libc::MS_NOSUID | libc::MS_NODEV, null as *const libc::c_void))?;
+    // Shows the processes of the sandbox only. Some containers do not let
+    // a user namespace mount proc, the host's then stays visible.
+    libc::mount(p.proc_.as_ptr(), p.proc_dir.as_ptr(), p.proc_.as_ptr(), libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC, null as *const libc::c_void);
+    if !p.network {
+        loopback_up();
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn write_file(path: &CString, data: &[u8]) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let n = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+    let e = io::Error::last_os_error();
+    libc::close(fd);
+    if n != data.len() as isize {
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn set_read_only(path: &CString, read_only: bool) -> io::Result<()> {
+    let attr = MountAttr {
+        attr_set: if read_only { MOUNT_ATTR_RDONLY } else { 0 },
+        attr_clr: if read_only { 0 } else { MOUNT_ATTR_RDONLY },
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let r = libc::syscall(libc::SYS_mount_setattr, libc::AT_FDCWD, path.as_ptr(), AT_RECURSIVE, &attr as *const MountAttr, std::mem::size_of::<MountAttr>());
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+/// A new network namespace has its loopback device down.
+unsafe fn loopback_up() {
+    let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+    if fd < 0 {
+        return;
+    }
+    let mut req = IfReq { name: [0; 16], flags: 0, _pad: [0; 22] };
+    req.name[..2].copy_from_slice(b"lo");
+    if libc::ioctl(fd, SIOCGIFFLAGS, &mut req as *mut IfReq) == 0 {
+        req.flags |= libc::IFF_UP as libc::c_short;
+        libc::ioctl(fd, SIOCSIFFLAGS, &req as *const IfReq);
+    }
+    libc::close(fd);
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::Sandbox;
+    use crate::testutil::TempDir;
+    use std::process::Command;
+
+    #[test]
+    fn test_sandbox() {
+        assert!(Sandbox { scratch: "tmp".to_owned(), ..Sandbox::default() }.validate().is_err());
+        let s: Sandbox = serde_json::from_str("{}").unwrap();
+        assert_eq!(s, Sandbox::default());
+    }
+
+    #[test]
+    #[ignore = "needs user namespaces, which containers and some kernels refuse"]
+    fn test_sandbox_run() {
+        let dir = TempDir::new("sandbox");
+        let writable = std::fs::canonicalize(&dir).unwrap().to_string_lossy().into_owned();
+        let sandbox = Sandbox { scratch: "/mnt".to_owned(), writable: vec![writable.clone()], ..Sandbox::default() };
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(format!(
+            "echo $$; touch /etc/queenbee-sandbox 2>/dev/null && echo etc; touch /mnt/x && echo scratch; touch {}/x && echo writable; grep -c : /proc/net/dev",
+            writable));
+        sandbox.apply(&mut c).unwrap();
+        let out = c.output().unwrap();
+        let lines: Vec<String> = String::from_utf8_lossy(&out.stdout).lines().map(str::to_owned).collect();
+        assert_eq!(lines, ["1", "scratch", "writable", "1"]);
+        assert!(dir.join("x").exists());
+
+        let mut c = Command::new("sh");
+        c.args(["-c", "exit 7"]);
+        sandbox.apply(&mut c).unwrap();
+        assert_eq!(c.status().unwrap().code(), Some(7));
+    }
+}
@@ -0,0 +1,239 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use crate::deploy::template::Vars;
+use crate::event::{Event, Observer};
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// What replaces a secret value wherever it would be shown.
+pub const MASK: &str = "***";
+
+/// Where the value of a secret comes from, exactly one of `file` and `env`.
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, Default)]
+pub struct SecretSource {
+    /// File holding the value, relative to `config_dir`. A trailing line
+    /// break is dropped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Environment variable of queenbee itself holding the value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<String>,
+    /// Also hands the value to commands in this environment variable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub export: Option<String>,
+}
+
+impl SecretSource {
+    /// Where the value is read from, for messages. Never the value itself.
+    pub fn describe(&self) -> String {
+        match (&self.file, &self.env) {
+            (Some(f), _) => format!("file `{}`", f),
+            (_, Some(e)) => format!("env `{}`", e),
+            _ => "nothing".to_owned(),
+        }
+    }
+}
+
+/// Resolved secret values. Templates see them as `{{ secrets.<name> }}`
+/// and every place showing output runs it through `redact`. `Debug` only
+/// shows the names.
+#[derive(Clone, Default)]
+pub struct Secrets {
+    values: BTreeMap<String, String>,
+    exports: HashMap<String, String>,
+    /// Values longest first, so one containing another is masked whole.
+    masked: Vec<String>,
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Secrets").field("names", &self.values.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+impl Secrets {
+    /// Reads every secret, files relative to `base` and environment
+    /// variables through `lookup`.
+    pub fn resolve<F: Fn(&str) -> Option<String>>(sources: &HashMap<String, SecretSource>, base: &Path, lookup: F) -> Result<Secrets, Error> {
+        let mut s = Secrets::default();
+        for (name, src) in sources {
+            let value = match (&src.file, &src.env) {
+                // Not through `integrity::read`, a lockfile holds no
+                // secrets and they change without locking again.
+                (Some(f), None) => {
+                    let v = fs::read_to_string(base.join(f))
+                        .map_err(|e| Error::Failed(format!("Cannot read secret `{}` from `{}`: {}", name, f, e)))?;
+                    v.strip_suffix('\n').map(|v| v.strip_suffix('\r').unwrap_or(v)).unwrap_or(&v).to_owned()
+                },
+                (None, Some(e)) => match lookup(e) {
+                    Some(v) => v,
+                    None => return Err(Error::Failed(format!("Secret `{}` expects the environment variable `{}`!", name, e))),
+                },
+                _ => return Err(Error::Failed(format!("Secret `{}` needs exactly one of `file` and `env`!", name))),
+            };
+            s.insert(name, value, src.export.as_deref());
+        }
+        Ok(s)
+    }
+
+    /// Adds one secret, exported to commands as `export` if given.
+    pub fn insert(&mut self, name: &str, value: String, export: Option<&str>) {
+        if let Some(e) = export {
+            self.exports.insert(e.to_owned(), value.clone());
+        }
+        if !value.is_empty() && !self.masked.contains(&value) {
+            self.masked.push(value.clone());
+            self.masked.sort_by_key(|v| std::cmp::Reverse(v.len()));
+        }
+        self.values.insert(name.to_owned(), value);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Template variables, `secrets.<name>` for every secret.
+    pub fn vars(&self) -> Vars {
+        self.values.iter().map(|(k, v)| (format!("secrets.{}", k), v.clone())).collect()
+    }
+
+    /// Environment variables handed to commands.
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.exports
+    }
+
+    /// `s` with every secret value replaced by `MASK`.
+    pub fn redact(&self, s: &str) -> String {
+        let mut out = s.to_owned();
+        for v in &self.masked {
+            if out.contains(v.as_str()) {
+                out = out.replace(v.as_str(), MASK);
+            }
+        }
+        out
+    }
+}
+
+/// Passes events on with secret values masked.
+pub struct Redacting<'a> {
+    pub inner: &'a dyn Observer,
+    pub secrets: &'a Secrets,
+}
+
+impl Observer for Redacting<'_> {
+    fn on_event(&self, event: &Event) {
+        if self.secrets.is_empty() {
+            self.inner.on_event(event);
+        } else {
+            self.inner.on_event(&event.map_text(|s| self.secrets.redact(s)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Redacting, SecretSource, Secrets};
+    use crate::event::{Event, Observer};
+    use crate::integrity;
+    use crate::testutil::TempDir;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_resolve() {
+        let mut sources = HashMap::new();
+        sources.insert("token".to_owned(), SecretSource { file: Some("secret.txt".to_owned()), ..Default::default() });
+        sources.insert("db".to_owned(), SecretSource { env: Some("DB_PASS".to_owned()), export: Some("PGPASSWORD".to_owned()), ..Default::default() });
+        let env = |k: &str| if k == "DB_PASS" { Some("hunter2".to_owned()) } else { None };
+        let s = Secrets::resolve(&sources, Path::new("./testresource"), env).unwrap();
+        assert_eq!(s.vars()["secrets.token"], "s3cr3t-token");
+        assert_eq!(s.vars()["secrets.db"], "hunter2");
+        assert_eq!(s.env()["PGPASSWORD"], "hunter2");
+        assert!(!format!("{:?}", s).contains("hunter2"));
+        assert_eq!(s.redact("curl -H s3cr3t-token -u admin:hunter2"), "curl -H *** -u admin:***");
+
+        assert!(Secrets::resolve(&sources, Path::new("./testresource"), |_| None).is_err());
+        sources.insert("both".to_owned(), SecretSource { file: Some("a".to_owned()), env: Some("B".to_owned()), ..Default::default() });
+        assert!(Secrets::resolve(&sources, Path::new("./testresource"), env).is_err());
+    }
+
+    #[test]
+    fn test_resolve_in_locked_dir() {
+        let dir = TempDir::new("secret-locked");
+        std::fs::write(dir.join("config.json"), "{}").unwrap();
+        std::fs::write(dir.join("token"), "t0ken\n").unwrap();
+        integrity::write_lock(&dir, &[dir.join("config.json")], None).unwrap();
+        let mut sources = HashMap::new();
+        sources.insert("token".to_owned(), SecretSource { file: Some("token".to_owned()), ..Default::default() });
+        let s = Secrets::resolve(&sources, &dir, |_| None).unwrap();
+        assert_eq!(s.vars()["secrets.token"], "t0ken");
+    }
+
+    #[test]
+    fn test_redact_longest_first() {
+        let mut s = Secrets::default();
+        s.insert("short", "abc".to_owned(), None);
+        s.insert("long", "abcdef".to_owned(), None);
+        s.insert("empty", String::new(), None);
+        assert_eq!(s.redact("x abcdef abc"), "x *** ***");
+    }
+
+    #[test]
+    fn test_redacting_observer() {
+        struct Last(Mutex<Option<Event>>);
+        impl Observer for Last {
+            fn on_event(&self, e: &Event) {
+                *self.0.lock().unwrap() = Some(e.clone());
+            }
+        }
+        let last = Last(Mutex::new(None));
+        let mut secrets = Secrets::default();
+        secrets.insert("pw", "hunter2".to_owned(), None);
+        let o = Redacting { inner: &last, secrets: &secrets };
+        o.on_event(&Event::CommandStarted {
+            plan: "p".to_owned(),
+            task: "t".to_owned(),
+            item: Some("pw=hunter2".to_owned()),
+            command: "/opt/hunter2/mysql".to_owned(),
+            args: vec!["-phunter2".to_owned()],
+        });
+        let e = last.0.lock().unwrap().take().unwrap();
+        match e {
+            Event::CommandStarted { item, command, args, .. } => {
+                assert_eq!(item.as_deref(), Some("pw=***"));
+                assert_eq!(command, "/opt/***/mysql");
+                assert_eq!(args, vec!["-p***".to_owned()]);
+            },
+            e => panic!("Unexpected event {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! MIT License
+
+//! Copyright (c) [year] [fullname]
+
+//! Permission is hereby granted, free of charge, to any person obtaining a copy
+//! of this software and associated documentation files (the "Software"), to deal
+//! in the Software without restriction, including without limitation the rights
+//! to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+//! copies of the Software, and to permit persons to whom the Software is
+//! furnished to do so, subject to the following conditions:
+
+//! The above copyright notice and this permission notice shall be included in all
+//! copies or substantial portions of the Software.
+
+//! THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+//! IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//! FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//! AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//! LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+//! SOFTWARE.
+//! 
+use crate::Error;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Bound to every ciphertext, so a vault cannot pass for anything else.
+const AAD: &[u8] = b"queenbee-vault-v1";
+
+/// A file of named secrets, encrypted as a whole with ChaCha20-Poly1305
+/// under a key derived with Argon2id from a passphrase or the contents of
+/// a key file. Nothing is decrypted before `open` is given the passphrase.
+pub struct Vault {
+    path: PathBuf,
+    header: Header,
+    key: [u8; 32],
+    entries: BTreeMap<String, String>,
+}
+
+/// What is stored on disk, all binary fields in base64.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Header {
+    version: u32,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+    #[serde(default)]
+    nonce: String,
+    #[serde(default)]
+    ciphertext: String,
+}
+
+impl Vault {
+    /// Opens the vault at `path` with `passphrase`, or starts an empty one
+    /// if there is no file yet.
+    pub fn open<P: AsRef<Path>>(path: P, passphrase: &[u8]) -> Result<Vault, Error> {
+        let p = Params::default();
+        Self::open_with(path.as_ref(), passphrase, p.m_cost(), p.t_cost(), p.p_cost())
+    }
+
+    fn open_with(path: &Path, passphrase: &[u8], m_cost: u32, t_cost: u32, p_cost: u32) -> Result<Vault, Error> {
+        if !path.exists() {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let header = Header { version: 1, m_cost, t_cost, p_cost, salt: BASE64.encode(salt), nonce: String::new(), ciphertext: String::new() };
+            let key = derive_key(&header, passphrase)?;
+            return Ok(Vault { path: path.to_path_buf(), header, key, entries: BTreeMap::new() });
+        }
+        let header: Header = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if header.version != 1 {
+            return Err(Error::Failed(format!("Vault `{}` has unknown version {}!", path.display(), header.version)));
+        }
+        let key = derive_key(&header, passphrase)?;
+        let nonce = decode(&header.nonce)?;
+        if nonce.len() != 12 {
+            return Err(Error::Failed(format!("Vault `{}` is damaged!", path.display())));
+        }
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let plain = cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: &decode(&header.ciphertext)?, aad: AAD })
+            .map_err(|_| Error::Failed(format!("Cannot decrypt vault `{}`, wrong passphrase or key file?", path.display())))?;
+        let entries = serde_json::from_slice(&plain)?;
+        Ok(Vault { path: path.to_path_buf(), header, key, entries })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries.get(name).map(|s| s.as_str())
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.entries.insert(name.to_owned(), value.to_owned());
+    }
+
+    /// Removes `name`, telling whether it was there.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.entries.remove(name).is_some()
+    }
+
+    /// Names of the entries, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        self.entries.keys().map(|k| k.as_str()).collect()
+    }
+
+    pub fn entries(&self) -> &BTreeMap<String, String> {
+        &self.entries
+    }
+
+    /// Encrypts the entries under a fresh nonce and replaces the file,
+    /// readable by its owner only.
+    pub fn save(&mut self) -> Result<(), Error> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let plain = serde_json::to_vec(&self.entries)?;
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: &plain, aad: AAD })
+            .map_err(|_| Error::from("Cannot encrypt the vault!"))?;
+        self.header.nonce = BASE64.encode(nonce);
+        self.header.ciphertext = BASE64.encode(ciphertext);
+
+        let tmp = self.path.with_extension("tmp");
+        write_private(&tmp, serde_json::to_string_pretty(&self.header)?.as_bytes())?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn derive_key(h: &Header, passphrase: &[u8]) -> Result<[u8; 32], Error> {
+    let params = Params::new(h.m_cost, h.t_cost, h.p_cost, Some(32))
+        .map_err(|e| Error::Failed(format!("Bad vault key parameters: {}", e)))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, &decode(&h.salt)?, &mut key)
+        .map_err(|e| Error::Failed(format!("Cannot derive the vault key: {}", e)))?;
+    Ok(key)
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, Error> {
+    BASE64.decode(s).map_err(|e| Error::Failed(format!("Vault is damaged: {}", e)))
+}
+
+#[cfg(unix)]
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<(), Error> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut f = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    f.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<(), Error> {
+    fs::write(path, data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Vault;
+    use crate::testutil::TempDir;
+    use std::fs;
+
+    fn open(path: &std::path::Path, pass: &[u8]) -> Result<Vault, crate::Error> {
+        // Cheap parameters, the defaults are slow without optimizations.
+        Vault::open_with(path, pass, 64, 1, 1)
+    }
+
+    #[test]
+    fn test_vault() {
+        let dir = TempDir::new("vault");
+        let path = dir.join("secrets.vault");
+
+        let mut v = open(&path, b"correct horse").unwrap();
+        assert!(v.names().is_empty());
+        v.set("db", "hunter2");
+        v.set("token", "abc");
+        v.save().unwrap();
+        let raw = fs::read_to_string(&path).unwrap();
+        assert!(!raw.contains("hunter2") && !raw.contains("token"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let mut v = open(&path, b"correct horse").unwrap();
+        assert_eq!(v.names(), vec!["db", "token"]);
+        assert_eq!(v.get("db"), Some("hunter2"));
+        assert!(v.remove("token"));
+        assert!(!v.remove("token"));
+        v.save().unwrap();
+        assert_eq!(open(&path, b"correct horse").unwrap().names(), vec!["db"]);
+        assert!(open(&path, b"wrong").is_err());
+    }
+}